@@ -1,5 +1,21 @@
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{AccountId, Balance};
+
+/// version of the newest `VersionedBalances` variant
+pub const LATEST_BALANCES_VERSION: u8 = 2;
+
+/// Key of the `hashes` record `account_id` keeps under `salt`. Account ids can't contain `:`, so
+/// keys of different accounts never collide.
+pub fn derive_key(account_id: &AccountId, salt: &str) -> String {
+    format!("{}:{}", account_id, salt)
+}
+
+/// whether `k` is a key `account_id` keeps under some salt, see `derive_key`
+pub fn is_own_key(account_id: &AccountId, k: &str) -> bool {
+    k.strip_prefix(account_id.as_str())
+        .map_or(false, |salt| salt.starts_with(':'))
+}
 
 #[derive(BorshDeserialize, BorshSerialize, Debug, PartialEq, Clone, Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
@@ -21,42 +37,91 @@ pub struct BalancesV1 {
     pub earned: u128,
 }
 
-/// An example of a versioned struct. In v1, earned is added to state
+#[derive(BorshDeserialize, BorshSerialize, Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BalancesV2 {
+    /// stores the amount given address deposited
+    pub deposited: u128,
+    /// stores the amount given address deposited plus the earned shares
+    pub total: u128,
+    pub earned: u128,
+    /// block timestamp of the latest write, 0 for records upgraded from an older version
+    pub updated_at: u64,
+    /// soft-deleted records keep their data but are hidden from normal reads
+    pub deleted: bool,
+}
+
+impl BalancesV2 {
+    /// whether the amounts are consistent: `total` covers both `deposited` and `earned`
+    pub fn is_valid(&self) -> bool {
+        self.total >= self.deposited && self.earned <= self.total
+    }
+}
+
+/// An example of a versioned struct. In v1, earned is added to state, in v2 the time of the
+/// latest write
 #[derive(BorshDeserialize, BorshSerialize, Debug, PartialEq, Clone)]
 pub enum VersionedBalances {
     V0(Balances),
     V1(BalancesV1),
+    V2(BalancesV2),
 }
 
 impl VersionedBalances {
-    /// upgrade VersionedBalances to newer version
+    /// upgrade VersionedBalances to the latest version
     pub fn upgrade(&self) -> Self {
+        VersionedBalances::V2(self.clone().get_balance())
+    }
+
+    /// version of the stored balances
+    pub fn version(&self) -> u8 {
         match self {
-            VersionedBalances::V0(bal) => {
-                // upgrade state to V1
-                let new_bal: BalancesV1 = BalancesV1 {
-                    deposited: bal.deposited,
-                    total: bal.total,
-                    earned: 0,
-                };
-                VersionedBalances::V1(new_bal)
-            }
-            // no upgrade required
-            VersionedBalances::V1(bal) => VersionedBalances::V1(bal.clone()),
+            Self::V0(_) => 0,
+            Self::V1(_) => 1,
+            Self::V2(_) => 2,
         }
     }
 
+    /// whether the record is soft-deleted, only `V2` records can be
+    pub fn is_deleted(&self) -> bool {
+        matches!(self, VersionedBalances::V2(bal) if bal.deleted)
+    }
+
     pub fn need_upgrade(&self) -> bool {
-        match self {
-            Self::V0(_) => true,
-            Self::V1(_) => false,
-        }
+        self.version() < LATEST_BALANCES_VERSION
     }
 
-    pub fn get_balance(self) -> BalancesV1 {
+    /// Balances converted to the latest version. Fields added after the stored version start
+    /// from their defaults, so every variant can be read without upgrading it in storage first.
+    pub fn get_balance(self) -> BalancesV2 {
         match self {
-            VersionedBalances::V1(bal) => bal,
-            _ => unimplemented!(),
+            VersionedBalances::V0(bal) => BalancesV2 {
+                deposited: bal.deposited,
+                total: bal.total,
+                earned: 0,
+                updated_at: 0,
+                deleted: false,
+            },
+            VersionedBalances::V1(bal) => BalancesV2 {
+                deposited: bal.deposited,
+                total: bal.total,
+                earned: bal.earned,
+                updated_at: 0,
+                deleted: false,
+            },
+            VersionedBalances::V2(bal) => bal,
         }
     }
 }
+
+/// Tags the `hashes` record of an escrow deposit
+#[derive(BorshDeserialize, BorshSerialize, Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Escrow {
+    /// account that made the escrow deposit
+    pub depositor: AccountId,
+    /// account receiving the escrowed amount on release
+    pub release_to: AccountId,
+    /// amount held in escrow
+    pub amount: Balance,
+}
@@ -1,10 +1,16 @@
 use std::collections::HashMap;
 
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::store::UnorderedMap;
-use near_sdk::{AccountId, Balance};
+use near_sdk::collections::LookupSet;
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json::{json, Value};
+use near_sdk::store::{LookupMap, UnorderedMap};
+use near_sdk::{env, require, AccountId, Balance, Gas};
 
-use crate::balances::VersionedBalances;
+use crate::balances::{Escrow, VersionedBalances};
+use crate::events;
+use crate::funders::{DepositRecord, FunderInfo};
 
 #[derive(BorshDeserialize, BorshSerialize)]
 pub struct ContractV0 {
@@ -21,19 +27,540 @@ impl Default for ContractV0 {
     }
 }
 
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ContractV1 {
+    pub funders: UnorderedMap<AccountId, Balance>,
+    pub nonce: u64,
+    pub hashes: HashMap<String, VersionedBalances>,
+}
+
+impl Default for ContractV1 {
+    fn default() -> Self {
+        Self {
+            funders: UnorderedMap::new(b"f"),
+            nonce: 0,
+            hashes: HashMap::new(),
+        }
+    }
+}
+
 #[derive(BorshDeserialize, BorshSerialize)]
 pub struct Contract {
     pub funders: UnorderedMap<AccountId, Balance>,
     pub nonce: u64,
     pub hashes: HashMap<String, VersionedBalances>,
+    /// account allowed to change the contract settings
+    pub owner_id: AccountId,
+    /// when set, deposits require `hashes` records keyed by the depositor and the credited account
+    pub require_registration: bool,
+    /// number of events emitted so far, each event carries its own index
+    pub event_index: u64,
+    /// when set, non-critical reads log and return nothing instead of aborting the call
+    pub lenient_reads: bool,
+    /// per-funder data of the funders migrated to the new funder schema
+    pub funder_info: LookupMap<AccountId, FunderInfo>,
+    /// sum of all `funders` balances
+    pub total_deposited: Balance,
+    /// lifetime sum of all withdrawals
+    pub total_withdrawn: Balance,
+    pub limits: Limits,
+    /// fee taken from each deposit, in basis points
+    pub fee_bps: u16,
+    /// account credited with the deposit fees, no fee is taken while unset
+    pub fee_account: Option<AccountId>,
+    /// rounds fees half up instead of truncating them
+    pub fee_round_up: bool,
+    /// block height `current_block_deposits` refers to
+    pub current_block: u64,
+    /// sum of the deposits accepted in `current_block`
+    pub current_block_deposits: Balance,
+    /// when set, only accounts in `allowlist` can deposit or be credited deposits
+    pub allowlist_enabled: bool,
+    pub allowlist: LookupSet<AccountId>,
+    /// when set, `withdraw` is rejected while deposits keep working
+    pub withdrawals_paused: bool,
+    /// escrow tags of the `hashes` records created by `deposit_escrow`
+    pub escrows: LookupMap<String, Escrow>,
+    /// escrows whose release transfer waits for its `on_escrow_released` callback
+    pub releasing_escrows: LookupSet<String>,
+    /// latest deposits, oldest first, at most `MAX_RECENT_DEPOSITS`
+    pub recent_deposits: Vec<DepositRecord>,
+    /// whether `deploy_code` can deploy new code
+    pub code_upgrades_allowed: bool,
+    /// account allowed to `deploy_code`, such as a DAO, the owner when unset
+    pub upgrader_id: Option<AccountId>,
+    /// number of state migrations applied to reach this version
+    pub migrations: u64,
+    /// short profile notes set by the accounts themselves
+    pub profiles: LookupMap<AccountId, String>,
+    /// parameters the state was created with
+    pub init_params: InitParams,
+    /// funding goal for `total_deposited`, 0 when there is none
+    pub goal: Balance,
+    /// set once `total_deposited` reaches `goal`, withdrawals are locked from then on
+    pub goal_reached: bool,
+    /// prepended to the `standard` of emitted events, to tell instances sharing an indexer apart
+    pub event_prefix: String,
+    /// length of the referral chain given with a funder's `deposit_with_chain`
+    pub referral_depths: LookupMap<AccountId, u8>,
+    /// gas attached to the `on_withdraw_complete` callback of each withdrawal
+    pub callback_gas: Gas,
+    /// accounts that can't deposit or be credited deposits
+    pub banned: LookupSet<AccountId>,
+    /// `(from_version, to_version, block_timestamp)` of the latest state upgrades, oldest first,
+    /// at most `MAX_UPGRADE_LOG`
+    pub upgrade_log: Vec<(u8, u8, u64)>,
+    /// account proposed as the next owner, it becomes the owner once it accepts
+    pub pending_owner: Option<AccountId>,
+    /// number of withdrawals per account whose `on_withdraw_complete` callback hasn't run yet
+    pub pending_withdrawals: LookupMap<AccountId, u32>,
+    /// accounts sharing the deposit fees with their share in basis points, replaces
+    /// `fee_account` as fee recipient while set
+    pub fee_recipients: Vec<(AccountId, u16)>,
+    /// account sharing the admin rights until the block timestamp next to it
+    pub delegate: Option<(AccountId, u64)>,
+    /// number of legacy funders moved to the `FunderInfo` schema
+    pub funders_migrated: u64,
+    /// accounts whose balance can't leave the contract, move to another funder or record, or be
+    /// swept or zeroed by the owner
+    pub frozen: LookupSet<AccountId>,
+    /// block timestamp of each account's latest deposit of its own funds, `deposit_cooldown`
+    /// counts from it
+    pub own_deposit_at: LookupMap<AccountId, u64>,
+}
+
+/// Parameters a contract state was created with, for auditing how it was deployed
+#[derive(BorshDeserialize, BorshSerialize, Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct InitParams {
+    pub owner_id: AccountId,
+    /// block timestamp the state was initialized, or upgraded to v2 at
+    pub created_at: u64,
+    pub limits: Limits,
+}
+
+/// Configurable bounds of the contract. `Default` holds the values used when migrating to v2.
+#[derive(
+    BorshDeserialize, BorshSerialize, Debug, PartialEq, Clone, Default, Serialize, Deserialize,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Limits {
+    /// upper bound for `total_deposited`, deposits above it are partially refunded
+    pub max_total: Option<U128>,
+    /// lower bound for a single deposit
+    pub min_deposit: Option<U128>,
+    /// upper bound for the number of funders, deposits of new accounts are rejected above it
+    pub max_funders: Option<u64>,
+    /// Upper bound for the balance of a single account, the part of a deposit above it is
+    /// refunded. Fees and withdrawals don't count toward it, so an account can send more than
+    /// the cap over its lifetime.
+    pub max_per_account: Option<U128>,
+    /// seconds an account has to wait after a deposit before depositing again
+    pub deposit_cooldown: Option<u64>,
 }
 
+/// An admin change applied by `batch_admin`
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum AdminOp {
+    /// pauses withdrawals
+    Pause,
+    /// unpauses withdrawals
+    Unpause,
+    SetMinDeposit(U128),
+    SetMaxTotal(Option<U128>),
+    /// rejects further deposits of the account, the owner can't be banned
+    Ban(AccountId),
+    Unban(AccountId),
+}
+
+/// number of deposits kept in `recent_deposits`
+pub const MAX_RECENT_DEPOSITS: usize = 20;
+/// number of upgrades kept in `upgrade_log`
+pub const MAX_UPGRADE_LOG: usize = 10;
+/// default gas for the `on_withdraw_complete` callback
+pub const CALLBACK_GAS: Gas = Gas(5_000_000_000_000);
+/// upper bound for the `on_withdraw_complete` callback gas, leaving room for the withdrawal
+/// itself within the gas of a single call
+pub const MAX_CALLBACK_GAS: Gas = Gas(100_000_000_000_000);
+/// gas attached to the `migrate` call following `deploy_code`
+pub const MIGRATE_GAS: Gas = Gas(20_000_000_000_000);
+
 impl Default for Contract {
     fn default() -> Self {
         Self {
             funders: UnorderedMap::new(b"f"),
             nonce: 0,
             hashes: HashMap::new(),
+            owner_id: env::current_account_id(),
+            require_registration: false,
+            event_index: 0,
+            lenient_reads: false,
+            funder_info: LookupMap::new(b"i"),
+            total_deposited: 0,
+            total_withdrawn: 0,
+            limits: Limits::default(),
+            fee_bps: 0,
+            fee_account: None,
+            fee_round_up: false,
+            current_block: 0,
+            current_block_deposits: 0,
+            allowlist_enabled: false,
+            allowlist: LookupSet::new(b"a"),
+            withdrawals_paused: false,
+            escrows: LookupMap::new(b"e"),
+            releasing_escrows: LookupSet::new(b"x"),
+            recent_deposits: Vec::new(),
+            code_upgrades_allowed: true,
+            upgrader_id: None,
+            migrations: 0,
+            profiles: LookupMap::new(b"p"),
+            init_params: InitParams {
+                owner_id: env::current_account_id(),
+                created_at: env::block_timestamp(),
+                limits: Limits::default(),
+            },
+            goal: 0,
+            goal_reached: false,
+            event_prefix: events::DEFAULT_EVENT_PREFIX.to_string(),
+            referral_depths: LookupMap::new(b"r"),
+            callback_gas: CALLBACK_GAS,
+            banned: LookupSet::new(b"b"),
+            upgrade_log: Vec::new(),
+            pending_owner: None,
+            pending_withdrawals: LookupMap::new(b"w"),
+            fee_recipients: Vec::new(),
+            delegate: None,
+            funders_migrated: 0,
+            frozen: LookupSet::new(b"z"),
+            own_deposit_at: LookupMap::new(b"c"),
+        }
+    }
+}
+
+/// system accounts that can't sign transactions and would lock the contract if made its owner
+pub const RESERVED_ACCOUNTS: [&str; 2] = ["system", "registrar"];
+
+/// Rejects owners nobody can act for by accident: implicit accounts (64 hex characters, usually a
+/// mistyped public key) and reserved system accounts.
+pub fn assert_valid_owner(account_id: &AccountId) {
+    let id = account_id.as_str();
+    let implicit = id.len() == 64 && id.bytes().all(|b| b.is_ascii_hexdigit());
+    require!(
+        !implicit && !RESERVED_ACCOUNTS.contains(&id),
+        "ERR_INVALID_OWNER"
+    );
+}
+
+/// How an attached deposit is split, see `Contract::plan_deposit`
+#[derive(Debug, PartialEq)]
+pub struct DepositPlan {
+    /// amount added to the depositor's balance
+    pub credited: Balance,
+    /// amount added to the fee account's balance
+    pub fee: Balance,
+    /// amount sent back to the depositor
+    pub refund: Balance,
+}
+
+impl Contract {
+    /// whether `account_id` may call the admin methods: the owner, or the delegate until its
+    /// delegation expires
+    pub fn is_admin(&self, account_id: &AccountId) -> bool {
+        account_id == &self.owner_id
+            || self
+                .delegate
+                .as_ref()
+                .map_or(false, |(delegate, until_ts)| {
+                    delegate == account_id && env::block_timestamp() < *until_ts
+                })
+    }
+
+    pub fn assert_owner(&self) {
+        require!(
+            self.is_admin(&env::predecessor_account_id()),
+            "ERR_NOT_OWNER"
+        );
+    }
+
+    /// like `assert_owner` but without the delegate, for changes of the admin rights themselves
+    pub fn assert_strict_owner(&self) {
+        require!(
+            env::predecessor_account_id() == self.owner_id,
+            "ERR_NOT_OWNER"
+        );
+    }
+
+    /// Splits an attached deposit into credited amount, fee and refund. The `max_total` cap
+    /// applies to everything the deposit adds to `total_deposited`, fee included, so the part
+    /// above the cap is refunded first and the fee is then taken from the accepted rest.
+    pub fn plan_deposit(&self, amount: Balance) -> DepositPlan {
+        self.plan_deposit_within(amount, Balance::MAX)
+    }
+
+    /// `plan_deposit` for a deposit by `account_id`, which also keeps the account's balance
+    /// within `max_per_account`. Only up to the room left under the cap is accepted, so the
+    /// balance after the fee never exceeds it.
+    pub fn plan_deposit_for(&self, account_id: &AccountId, amount: Balance) -> DepositPlan {
+        let room = match self.limits.max_per_account {
+            Some(max_per_account) => {
+                let balance = self.funders.get(account_id).copied().unwrap_or(0);
+                max_per_account.0.saturating_sub(balance)
+            }
+            None => Balance::MAX,
+        };
+        self.plan_deposit_within(amount, room)
+    }
+
+    fn plan_deposit_within(&self, amount: Balance, room: Balance) -> DepositPlan {
+        let accepted = match self.limits.max_total {
+            Some(max_total) => amount.min(max_total.0.saturating_sub(self.total_deposited)),
+            None => amount,
+        }
+        .min(room);
+        let fee = self.fee_for(accepted);
+        DepositPlan {
+            credited: accepted - fee,
+            fee,
+            refund: amount - accepted,
+        }
+    }
+
+    /// Fee taken from a deposit of `amount`. Rounding half up takes the extra yoctoNEAR from the
+    /// credited remainder, so the depositor never gets more than `amount - fee`.
+    pub fn fee_for(&self, amount: Balance) -> Balance {
+        if self.fee_account.is_none() && self.fee_recipients.is_empty() {
+            return 0;
+        }
+        let fee = amount * self.fee_bps as Balance;
+        if self.fee_round_up {
+            (fee + 5_000) / 10_000
+        } else {
+            fee / 10_000
+        }
+    }
+
+    /// adds `amount` to the balance of `account_id`, returning the new balance
+    pub fn credit(&mut self, account_id: &AccountId, amount: Balance) -> Balance {
+        self.accrue_twab(account_id);
+        let balance = self.funders.entry(account_id.clone()).or_default();
+        *balance += amount;
+        let new_balance = *balance;
+        self.total_deposited += amount;
+        self.update_goal_reached();
+        new_balance
+    }
+
+    /// Credits a collected fee to the fee recipients in proportion of their share, the rounding
+    /// remainder goes to the first one. Without recipients the whole fee goes to `fee_account`.
+    /// Recipients are funders like any other, a new one takes one of the `max_funders` slots.
+    pub fn credit_fee(&mut self, fee: Balance) {
+        if self.fee_recipients.is_empty() {
+            let fee_account = self.fee_account.clone().expect("ERR_NO_FEE_ACCOUNT");
+            self.admit_funder(&fee_account);
+            self.credit(&fee_account, fee);
+            return;
+        }
+        let recipients = self.fee_recipients.clone();
+        let shares: Vec<Balance> = recipients
+            .iter()
+            .map(|(_, bps)| fee * *bps as Balance / 10_000)
+            .collect();
+        let remainder = fee - shares.iter().sum::<Balance>();
+        for (i, ((account_id, _), share)) in recipients.iter().zip(shares).enumerate() {
+            let amount = if i == 0 { share + remainder } else { share };
+            self.admit_funder(account_id);
+            self.credit(account_id, amount);
+        }
+    }
+
+    /// flips `goal_reached` once `total_deposited` reaches the goal, it never flips back
+    pub fn update_goal_reached(&mut self) {
+        if self.goal > 0 && self.total_deposited >= self.goal {
+            self.goal_reached = true;
+        }
+    }
+
+    /// removes `amount` from the balance of `account_id`, returning the new balance
+    pub fn debit(&mut self, account_id: &AccountId, amount: Balance) -> Balance {
+        self.accrue_twab(account_id);
+        let balance = self.funders.get_mut(account_id).expect("ERR_NOT_FUNDER");
+        require!(*balance >= amount, "ERR_INSUFFICIENT_BALANCE");
+        *balance -= amount;
+        self.total_deposited -= amount;
+        *balance
+    }
+
+    /// Prepares a credit to `account_id`: an account that isn't a funder yet takes one of the
+    /// `max_funders` slots, a legacy funder is upgraded.
+    pub fn admit_funder(&mut self, account_id: &AccountId) {
+        if !self.funders.contains_key(account_id) {
+            require!(self.funder_slots_remaining() > 0, "ERR_MAX_FUNDERS_REACHED");
+        }
+        self.upgrade_funder(account_id);
+    }
+
+    /// Removes `account_id` from the funders together with its per-funder data, returning the
+    /// balance it had, which is taken out of `total_deposited`
+    pub fn remove_funder(&mut self, account_id: &AccountId) -> Balance {
+        let balance = self.funders.remove(account_id).unwrap_or(0);
+        self.funder_info.remove(account_id);
+        self.own_deposit_at.remove(account_id);
+        self.referral_depths.remove(account_id);
+        self.total_deposited -= balance;
+        balance
+    }
+
+    /// funders that can still be added under `max_funders`, `u64::MAX` without a cap
+    pub fn funder_slots_remaining(&self) -> u64 {
+        match self.limits.max_funders {
+            Some(max_funders) => max_funders.saturating_sub(self.funders.len() as u64),
+            None => u64::MAX,
+        }
+    }
+
+    /// Panics unless `depositor` may credit a new deposit to `beneficiary`, which can be the
+    /// depositor itself. Both have to pass `assert_can_receive`, the depositor also has to be
+    /// past its `deposit_cooldown`. Every path crediting new deposits goes through this check.
+    pub fn assert_deposit_allowed(&self, depositor: &AccountId, beneficiary: &AccountId) {
+        self.assert_can_receive(depositor);
+        if beneficiary != depositor {
+            self.assert_can_receive(beneficiary);
+        }
+        require!(
+            self.deposit_cooldown_remaining(depositor) == 0,
+            "ERR_DEPOSIT_COOLDOWN"
+        );
+    }
+
+    /// panics unless `account_id` is registered and allowlisted when those are required, and
+    /// isn't banned. A soft-deleted record doesn't count as a registration.
+    pub fn assert_can_receive(&self, account_id: &AccountId) {
+        if self.require_registration {
+            require!(
+                self.hashes
+                    .get(account_id.as_str())
+                    .map_or(false, |versioned| !versioned.is_deleted()),
+                "ERR_NOT_REGISTERED"
+            );
+        }
+        if self.allowlist_enabled {
+            require!(self.allowlist.contains(account_id), "ERR_NOT_ALLOWLISTED");
+        }
+        require!(!self.banned.contains(account_id), "ERR_BANNED");
+    }
+
+    /// seconds left before `account_id` can deposit again under `deposit_cooldown`
+    pub fn deposit_cooldown_remaining(&self, account_id: &AccountId) -> u64 {
+        match (
+            self.limits.deposit_cooldown,
+            self.own_deposit_at.get(account_id),
+        ) {
+            (Some(cooldown), Some(deposit_at)) => {
+                let elapsed = (env::block_timestamp() - deposit_at) / 1_000_000_000;
+                cooldown.saturating_sub(elapsed)
+            }
+            _ => 0,
         }
     }
+
+    /// Panics if `op` can't be applied. `max_total` is the cap in effect when `op` runs, so
+    /// limits set earlier in the same batch are taken into account.
+    pub fn validate_admin_op(&self, op: &AdminOp, max_total: Option<U128>) {
+        match op {
+            AdminOp::SetMinDeposit(min_deposit) => require!(
+                max_total.map_or(true, |max_total| min_deposit.0 <= max_total.0),
+                "ERR_INVALID_LIMITS"
+            ),
+            AdminOp::Ban(account_id) => {
+                require!(!self.is_admin(account_id), "ERR_CANNOT_BAN_OWNER")
+            }
+            _ => {}
+        }
+    }
+
+    pub fn apply_admin_op(&mut self, op: AdminOp) {
+        match op {
+            AdminOp::Pause => self.withdrawals_paused = true,
+            AdminOp::Unpause => self.withdrawals_paused = false,
+            AdminOp::SetMinDeposit(min_deposit) => self.limits.min_deposit = Some(min_deposit),
+            AdminOp::SetMaxTotal(max_total) => self.limits.max_total = max_total,
+            AdminOp::Ban(account_id) => {
+                self.banned.insert(&account_id);
+            }
+            AdminOp::Unban(account_id) => {
+                self.banned.remove(&account_id);
+            }
+        }
+    }
+
+    /// brings the time-weighted balance of `account_id` up to date before its balance changes
+    fn accrue_twab(&mut self, account_id: &AccountId) {
+        let balance = self.funders.get(account_id).copied().unwrap_or(0);
+        if let Some(info) = self.funder_info.get_mut(account_id) {
+            info.accrue(balance, env::block_timestamp());
+        }
+    }
+
+    /// adds `amount` to the deposits of the current block, starting over on a new block
+    pub fn track_block_deposit(&mut self, amount: Balance) {
+        let block_height = env::block_height();
+        if self.current_block != block_height {
+            self.current_block = block_height;
+            self.current_block_deposits = 0;
+        }
+        self.current_block_deposits += amount;
+    }
+
+    /// records a deposit to `account_id` in its funder info, upgrading legacy funders
+    pub fn touch_funder_info(&mut self, account_id: &AccountId) {
+        let now = env::block_timestamp();
+        let nonce = self.nonce;
+        let info = self
+            .funder_info
+            .entry(account_id.clone())
+            .or_insert_with(|| FunderInfo::new(now, nonce));
+        info.first_deposit_at.get_or_insert(now);
+        info.last_deposit_at = Some(now);
+        info.last_deposit_nonce = Some(nonce);
+    }
+
+    /// adds an upgrade to `upgrade_log`, dropping the oldest one when full
+    pub fn record_upgrade(&mut self, from_version: u8, to_version: u8) {
+        if self.upgrade_log.len() >= MAX_UPGRADE_LOG {
+            self.upgrade_log.remove(0);
+        }
+        self.upgrade_log
+            .push((from_version, to_version, env::block_timestamp()));
+    }
+
+    /// Moves a legacy funder, one with a balance but no `FunderInfo`, to the current schema.
+    /// Its deposit history is unknown and its time-weighted balance starts now. Returns whether
+    /// the funder was upgraded.
+    pub fn upgrade_funder(&mut self, account_id: &AccountId) -> bool {
+        if !self.funders.contains_key(account_id) || self.funder_info.contains_key(account_id) {
+            return false;
+        }
+        self.funder_info.insert(
+            account_id.clone(),
+            FunderInfo::migrated(env::block_timestamp()),
+        );
+        self.funders_migrated += 1;
+        true
+    }
+
+    /// adds a deposit to `recent_deposits`, dropping the oldest one when full
+    pub fn record_recent_deposit(&mut self, record: DepositRecord) {
+        if self.recent_deposits.len() >= MAX_RECENT_DEPOSITS {
+            self.recent_deposits.remove(0);
+        }
+        self.recent_deposits.push(record);
+    }
+
+    /// emits a NEP-297 event tagged with the next event index
+    pub fn emit_event(&mut self, event: &str, mut data: Value) {
+        data["event_index"] = json!(self.event_index);
+        events::emit(&self.event_prefix, event, data);
+        self.event_index += 1;
+    }
 }
@@ -0,0 +1,20 @@
+use near_sdk::env;
+use near_sdk::serde_json::{json, Value};
+
+/// `standard` field of the emitted NEP-297 events
+pub const EVENT_STANDARD: &str = "versioned_extended";
+/// prefix of the `standard` field unless the owner configures another one
+pub const DEFAULT_EVENT_PREFIX: &str = "";
+/// `version` field of the emitted NEP-297 events
+pub const EVENT_STANDARD_VERSION: &str = "1.0.0";
+
+/// logs `data` as a NEP-297 event, with `prefix` prepended to the standard
+pub fn emit(prefix: &str, event: &str, data: Value) {
+    let event = json!({
+        "standard": format!("{}{}", prefix, EVENT_STANDARD),
+        "version": EVENT_STANDARD_VERSION,
+        "event": event,
+        "data": [data],
+    });
+    env::log_str(&format!("EVENT_JSON:{}", event));
+}
@@ -0,0 +1,87 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{AccountId, Balance};
+
+/// schema version of funders that only have a bare `Balance` in `funders`
+pub const LEGACY_FUNDER_VERSION: u8 = 0;
+/// schema version of funders that also have a `FunderInfo` entry
+pub const FUNDER_INFO_VERSION: u8 = 1;
+
+/// Per-funder data stored next to the bare `Balance` kept in `funders`. Funders that haven't
+/// deposited since v2 have no entry and are still on the legacy schema. Legacy funders moved to
+/// this schema without depositing have no deposit history, their deposit fields stay `None`
+/// until their next deposit.
+#[derive(BorshDeserialize, BorshSerialize, Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FunderInfo {
+    /// block timestamp of the first deposit recorded with this schema
+    pub first_deposit_at: Option<u64>,
+    /// block timestamp of the latest deposit
+    pub last_deposit_at: Option<u64>,
+    /// value of the global nonce right after the latest deposit
+    pub last_deposit_nonce: Option<u64>,
+    /// block timestamp the funder moved to this schema, at its first deposit or its migration
+    pub tracked_since: u64,
+    /// sum of the balance times the seconds it was held, up to `accrued_at`
+    pub balance_seconds: u128,
+    /// block timestamp `balance_seconds` was last brought up to date
+    pub accrued_at: u64,
+}
+
+impl FunderInfo {
+    /// info of a funder whose first deposit is at `timestamp`
+    pub fn new(timestamp: u64, nonce: u64) -> Self {
+        Self {
+            first_deposit_at: Some(timestamp),
+            last_deposit_at: Some(timestamp),
+            last_deposit_nonce: Some(nonce),
+            ..Self::migrated(timestamp)
+        }
+    }
+
+    /// info of a legacy funder moved to this schema at `timestamp`, without a deposit
+    pub fn migrated(timestamp: u64) -> Self {
+        Self {
+            first_deposit_at: None,
+            last_deposit_at: None,
+            last_deposit_nonce: None,
+            tracked_since: timestamp,
+            balance_seconds: 0,
+            accrued_at: timestamp,
+        }
+    }
+
+    /// adds the time `balance` was held since `accrued_at` to `balance_seconds`
+    pub fn accrue(&mut self, balance: Balance, now: u64) {
+        let elapsed = (now.saturating_sub(self.accrued_at) / 1_000_000_000) as u128;
+        self.balance_seconds = self
+            .balance_seconds
+            .saturating_add(balance.saturating_mul(elapsed));
+        self.accrued_at = now;
+    }
+
+    /// average balance since `tracked_since`, given the current `balance`
+    pub fn twab(&self, balance: Balance, now: u64) -> Balance {
+        let mut info = self.clone();
+        info.accrue(balance, now);
+        match (now.saturating_sub(self.tracked_since) / 1_000_000_000) as u128 {
+            0 => balance,
+            held => info.balance_seconds / held,
+        }
+    }
+}
+
+/// A deposit kept in the bounded recent activity buffer
+#[derive(BorshDeserialize, BorshSerialize, Debug, PartialEq, Clone)]
+pub struct DepositRecord {
+    pub account_id: AccountId,
+    /// amount credited to the depositor
+    pub amount: Balance,
+    /// fee taken from the deposit
+    pub fee: Balance,
+    /// depositor's balance right after the deposit
+    pub new_total: Balance,
+    pub timestamp: u64,
+    /// index of the event emitted for the deposit
+    pub event_index: u64,
+}
@@ -1,42 +1,101 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{Base64VecU8, I128, U128};
+use near_sdk::serde_json::json;
 use near_sdk::store::UnorderedMap;
-use near_sdk::{env, log, near_bindgen, AccountId, Balance};
+use near_sdk::{env, log, near_bindgen, require, AccountId, Balance, Gas, Promise, PromiseResult};
 
 mod balances;
 mod contracts;
+mod events;
+mod funders;
+mod views;
 
 use balances::*;
 use contracts::*;
+use funders::*;
+use views::*;
+
+/// version of the newest `VersionedContract` variant
+pub const LATEST_STATE_VERSION: u8 = 2;
+/// version of the deployed code, bumped on every release independently of the state version
+pub const CONTRACT_VERSION: &str = "0.1.0";
+/// `(standard, version)` of the NEP standards this contract implements
+pub const SUPPORTED_STANDARDS: [(&str, &str); 1] = [("nep297", "1.0.0")];
+/// maximum number of accounts handled by a single batch call
+pub const MAX_BATCH_SIZE: usize = 32;
+/// number of referral levels paid by `deposit_with_chain`
+pub const MAX_REFERRAL_DEPTH: usize = 2;
+/// bonus paid to each referral level, in basis points of the credited deposit
+pub const REFERRAL_BONUS_BPS: [u16; MAX_REFERRAL_DEPTH] = [100, 50];
+/// maximum number of funders in a snapshot checked by `verify_funders_blob`
+pub const MAX_SNAPSHOT_SIZE: u64 = 100;
+/// maximum number of funders summed by `total_cache_consistent`
+pub const MAX_CONSISTENCY_CHECK_SIZE: u64 = 200;
+/// estimated storage of a new funder: its `funders` entry and `FunderInfo`, with the longest
+/// account id as key
+pub const FUNDER_STORAGE_BYTES: u64 = 400;
+/// maximum length of a profile note, in bytes
+pub const MAX_PROFILE_LENGTH: usize = 128;
+/// maximum length of the reason given for an admin balance change, in bytes
+pub const MAX_REASON_LENGTH: usize = 256;
+/// maximum length of the prefix prepended to the `standard` of events, in bytes
+pub const MAX_EVENT_PREFIX_LENGTH: usize = 32;
 
 /// An example of a versioned contract. This is a simple contract that tracks how much
 /// each account deposits into the contract. In v1, a nonce is added to state which increments
-/// after each successful deposit.
+/// after each successful deposit. In v2, an owner and contract settings are added to state.
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize)]
 pub enum VersionedContract {
     V0(ContractV0),
-    V1(Contract),
+    V1(ContractV1),
+    V2(Contract),
 }
 
 impl VersionedContract {
     fn contract_mut(&mut self) -> &mut Contract {
-        let old_contract = match self {
-            Self::V1(contract) => return contract,
+        let (from_version, funders, nonce, hashes) = match self {
+            // Already on the latest version, hand out the state as is without moving it.
+            Self::V2(contract) => return contract,
             Self::V0(contract) => {
                 // Contract state is old version, take old state to upgrade.
-                core::mem::take(contract)
+                let old_contract = core::mem::take(contract);
+                (0, old_contract.funders, 0, old_contract.hashes)
+            }
+            Self::V1(contract) => {
+                let old_contract = core::mem::take(contract);
+                (
+                    1,
+                    old_contract.funders,
+                    old_contract.nonce,
+                    old_contract.hashes,
+                )
             }
         };
 
         // Upgrade state of self and return mutable reference to it.
-        *self = Self::V1(Contract {
-            funders: old_contract.funders,
-            nonce: 0,
-            hashes: old_contract.hashes,
+        // Fields added in newer versions start from their defaults, the cached total has to be
+        // computed once from the existing funders.
+        let total_deposited = funders.values().sum();
+        *self = Self::V2(Contract {
+            funders,
+            nonce,
+            hashes,
+            total_deposited,
+            migrations: 1,
+            ..Default::default()
         });
-        if let Self::V1(contract) = self {
+        if let Self::V2(contract) = self {
+            contract.record_upgrade(from_version, LATEST_STATE_VERSION);
+            contract.emit_event(
+                "upgrade",
+                json!({
+                    "from_version": from_version,
+                    "to_version": LATEST_STATE_VERSION,
+                }),
+            );
             contract
         } else {
             // Variant is constructed above, this is unreachable
@@ -44,10 +103,19 @@ impl VersionedContract {
         }
     }
 
+    /// returns the contract state if it is already on the latest version
+    fn latest(&self) -> Option<&Contract> {
+        match self {
+            Self::V2(contract) => Some(contract),
+            _ => None,
+        }
+    }
+
     fn funders(&self) -> &UnorderedMap<AccountId, Balance> {
         match self {
             Self::V0(contract) => &contract.funders,
             Self::V1(contract) => &contract.funders,
+            Self::V2(contract) => &contract.funders,
         }
     }
 
@@ -55,142 +123,4306 @@ impl VersionedContract {
         match self {
             Self::V0(contract) => &contract.hashes,
             Self::V1(contract) => &contract.hashes,
+            Self::V2(contract) => &contract.hashes,
         }
     }
 
+    /// `hashes` entries sorted by key, so windows are stable across calls
+    fn hashes_page(&self, from_index: u64, limit: u64) -> Vec<(&String, &VersionedBalances)> {
+        let mut entries: Vec<_> = self.hashes().iter().collect();
+        entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+        entries
+            .into_iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
     fn hashes_mut(&mut self) -> &mut HashMap<String, VersionedBalances> {
         match self {
             Self::V0(contract) => &mut contract.hashes,
             Self::V1(contract) => &mut contract.hashes,
+            Self::V2(contract) => &mut contract.hashes,
+        }
+    }
+
+    /// Deposits `amount` for `account_id`. `unused` is the part of the attached deposit that
+    /// isn't offered for the deposit, it is refunded together with anything above the cap.
+    /// Returns the receipt of the deposit.
+    fn internal_deposit(
+        &mut self,
+        account_id: AccountId,
+        amount: Balance,
+        unused: Balance,
+    ) -> DepositReceipt {
+        let contract = self.contract_mut();
+        contract.assert_deposit_allowed(&account_id, &account_id);
+        if let Some(min_deposit) = contract.limits.min_deposit {
+            require!(amount >= min_deposit.0, "ERR_DEPOSIT_TOO_SMALL");
+        }
+        if !contract.funders.contains_key(&account_id) {
+            require!(
+                contract.funder_slots_remaining() > 0,
+                "ERR_MAX_FUNDERS_REACHED"
+            );
+        }
+        let plan = contract.plan_deposit_for(&account_id, amount);
+        require!(amount == 0 || plan.refund < amount, "ERR_MAX_TOTAL_REACHED");
+        contract.upgrade_funder(&account_id);
+        let new_total = contract.credit(&account_id, plan.credited);
+        if plan.fee > 0 {
+            contract.credit_fee(plan.fee);
+        }
+        if plan.refund + unused > 0 {
+            Promise::new(account_id.clone()).transfer(plan.refund + unused);
+        }
+        contract.track_block_deposit(plan.credited + plan.fee);
+        contract.nonce += 1;
+        contract.touch_funder_info(&account_id);
+        contract
+            .own_deposit_at
+            .insert(account_id.clone(), env::block_timestamp());
+        contract.record_recent_deposit(DepositRecord {
+            account_id: account_id.clone(),
+            amount: plan.credited,
+            fee: plan.fee,
+            new_total,
+            timestamp: env::block_timestamp(),
+            event_index: contract.event_index,
+        });
+        contract.emit_event(
+            "deposit",
+            json!({
+                "account_id": account_id,
+                "amount": U128(plan.credited),
+                "fee": U128(plan.fee),
+                "new_total": U128(new_total),
+            }),
+        );
+        DepositReceipt {
+            account_id,
+            amount: U128(plan.credited),
+            new_balance: U128(new_total),
+            nonce: contract.nonce,
+            timestamp: env::block_timestamp(),
         }
     }
 }
 
 impl Default for VersionedContract {
     fn default() -> Self {
-        VersionedContract::V1(Contract::default())
+        VersionedContract::V2(Contract::default())
+    }
+}
+
+#[cfg(test)]
+impl VersionedContract {
+    /// Rewrites the record under `k` as version `v`, to set up mixed-version state in tests.
+    /// Downgrading drops the fields the older version doesn't have.
+    pub fn force_balance_version(&mut self, k: String, v: u8) {
+        let hashes = self.hashes_mut();
+        let bal = hashes
+            .get(&k)
+            .expect("ERR_INVALID_KEY")
+            .upgrade()
+            .get_balance();
+        let versioned = match v {
+            0 => VersionedBalances::V0(Balances {
+                deposited: bal.deposited,
+                total: bal.total,
+            }),
+            1 => VersionedBalances::V1(BalancesV1 {
+                deposited: bal.deposited,
+                total: bal.total,
+                earned: bal.earned,
+            }),
+            2 => VersionedBalances::V2(bal),
+            _ => panic!("unknown balances version {}", v),
+        };
+        hashes.insert(k, versioned);
+    }
+
+    /// overwrites the cached total, to set up an inconsistent cache in tests
+    pub fn force_total_deposited(&mut self, total: Balance) {
+        self.contract_mut().total_deposited = total;
+    }
+}
+
+#[cfg(feature = "debug")]
+#[near_bindgen]
+impl VersionedContract {
+    /// borsh discriminant of the stored `VersionedContract` variant
+    pub fn variant_tag(&self) -> u8 {
+        self.try_to_vec().expect("ERR_SERIALIZE")[0]
     }
 }
 
 #[near_bindgen]
 impl VersionedContract {
+    #[init]
+    pub fn new(owner_id: AccountId, max_total: Option<U128>) -> Self {
+        assert_valid_owner(&owner_id);
+        let limits = Limits {
+            max_total,
+            ..Default::default()
+        };
+        Self::V2(Contract {
+            owner_id: owner_id.clone(),
+            limits: limits.clone(),
+            init_params: InitParams {
+                owner_id,
+                created_at: env::block_timestamp(),
+                limits,
+            },
+            ..Default::default()
+        })
+    }
+
+    /// Upgrades the stored state to the latest version right away, instead of on the first
+    /// call that mutates it. Meant to be called right after deploying new code.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let mut contract: Self = env::state_read().expect("ERR_NO_STATE");
+        contract.contract_mut();
+        contract
+    }
+
+    /// Deploys `code` on the contract account and calls `migrate` on it, as allowed by the
+    /// upgrade policy: `code_upgrades_allowed` has to be set and the caller has to be the
+    /// upgrader, or the owner when there is none.
+    pub fn deploy_code(&mut self, code: Base64VecU8) {
+        let account_id = env::predecessor_account_id();
+        let contract = self.contract_mut();
+        require!(contract.code_upgrades_allowed, "ERR_CODE_UPGRADES_DISABLED");
+        let upgrader_id = contract
+            .upgrader_id
+            .clone()
+            .unwrap_or_else(|| contract.owner_id.clone());
+        require!(account_id == upgrader_id, "ERR_NOT_UPGRADER");
+        contract.emit_event(
+            "deploy_code",
+            json!({
+                "account_id": account_id,
+                "code_size": code.0.len(),
+            }),
+        );
+        Promise::new(env::current_account_id())
+            .deploy_contract(code.0)
+            .function_call("migrate".to_string(), Vec::new(), 0, MIGRATE_GAS);
+    }
+
+    /// Upgrades the state to the latest version in place without doing anything else, returns
+    /// the new state version.
+    #[private]
+    pub fn force_contract_upgrade(&mut self) -> u8 {
+        self.contract_mut();
+        self.state_version()
+    }
+
     #[payable]
-    pub fn deposit(&mut self) {
+    pub fn deposit(&mut self) -> DepositReceipt {
         let account_id = env::predecessor_account_id();
         let deposit = env::attached_deposit();
         log!("{} deposited {} yNEAR", account_id, deposit);
 
+        self.internal_deposit(account_id, deposit, 0)
+    }
+
+    /// Alias of `deposit` for wallets configured to call a method named `__default`. It isn't
+    /// a fallback: NEAR has none, calls to unknown methods fail and plain transfers don't run
+    /// any contract code.
+    #[payable]
+    pub fn __default(&mut self) {
+        self.deposit();
+    }
+
+    /// Credits only what is needed to bring the caller's balance up to `target` and refunds the
+    /// rest of the attached deposit. The fee, if any, is taken from the credited part, so with a
+    /// fee configured the balance ends below `target` by that fee.
+    #[payable]
+    pub fn top_up_to(&mut self, target: U128) {
+        let account_id = env::predecessor_account_id();
+        let deposit = env::attached_deposit();
+        let balance = self.funders().get(&account_id).copied().unwrap_or(0);
+        require!(balance < target.0, "ERR_ALREADY_ABOVE_TARGET");
+        log!("{} topped up to {} yNEAR", account_id, target.0);
+
+        let amount = deposit.min(target.0 - balance);
+        self.internal_deposit(account_id, amount, deposit - amount);
+    }
+
+    /// Splits the attached deposit evenly between `beneficiaries`, the remainder of the division
+    /// goes to the first one. Takes at most `MAX_BATCH_SIZE` beneficiaries to keep gas bounded.
+    #[payable]
+    pub fn deposit_split(&mut self, beneficiaries: Vec<AccountId>) {
+        require!(!beneficiaries.is_empty(), "ERR_NO_BENEFICIARIES");
+        require!(beneficiaries.len() <= MAX_BATCH_SIZE, "ERR_BATCH_TOO_LARGE");
+        let account_id = env::predecessor_account_id();
+        let deposit = env::attached_deposit();
+
         let contract = self.contract_mut();
-        *contract.funders.entry(account_id).or_default() += deposit;
+        for beneficiary in &beneficiaries {
+            contract.assert_deposit_allowed(&account_id, beneficiary);
+        }
+        if let Some(min_deposit) = contract.limits.min_deposit {
+            require!(deposit >= min_deposit.0, "ERR_DEPOSIT_TOO_SMALL");
+        }
+        let plan = contract.plan_deposit(deposit);
+        require!(plan.refund == 0, "ERR_MAX_TOTAL_REACHED");
+        if plan.fee > 0 {
+            contract.credit_fee(plan.fee);
+        }
+
+        let new_funders = beneficiaries
+            .iter()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .filter(|beneficiary| !contract.funders.contains_key(*beneficiary))
+            .count();
+        require!(
+            contract.funder_slots_remaining() >= new_funders as u64,
+            "ERR_MAX_FUNDERS_REACHED"
+        );
+
+        let share = plan.credited / beneficiaries.len() as Balance;
+        let remainder = plan.credited % beneficiaries.len() as Balance;
         contract.nonce += 1;
+        for (i, beneficiary) in beneficiaries.iter().enumerate() {
+            let amount = if i == 0 { share + remainder } else { share };
+            contract.upgrade_funder(beneficiary);
+            let balance = contract.credit(beneficiary, amount);
+            if let Some(max_per_account) = contract.limits.max_per_account {
+                require!(balance <= max_per_account.0, "ERR_MAX_PER_ACCOUNT_REACHED");
+            }
+            contract.touch_funder_info(beneficiary);
+        }
+        contract.track_block_deposit(deposit);
+        contract
+            .own_deposit_at
+            .insert(account_id.clone(), env::block_timestamp());
+        contract.emit_event(
+            "deposit_split",
+            json!({
+                "account_id": account_id,
+                "beneficiaries": beneficiaries,
+                "amount": U128(plan.credited),
+                "fee": U128(plan.fee),
+            }),
+        );
     }
 
-    pub fn get_nonce(&self) -> u64 {
-        match self {
-            Self::V0(_) => 0,
-            Self::V1(contract) => contract.nonce,
+    /// Deposits the attached deposit and pays referral bonuses from the treasury (the fee
+    /// account) up the chain of `referrers`, closest referrer first. Each level gets
+    /// `REFERRAL_BONUS_BPS` of the credited amount, levels past `MAX_REFERRAL_DEPTH` are ignored
+    /// and bonuses stop once the treasury balance runs out. Paid referrers have to be allowed to
+    /// receive deposits, a bonus above `max_per_account` is cut to the room left.
+    #[payable]
+    pub fn deposit_with_chain(&mut self, referrers: Vec<AccountId>) {
+        let account_id = env::predecessor_account_id();
+        require!(!referrers.contains(&account_id), "ERR_SELF_REFERRAL");
+        require!(
+            referrers.iter().collect::<BTreeSet<_>>().len() == referrers.len(),
+            "ERR_DUPLICATE_REFERRER"
+        );
+        let depth = referrers.len().min(MAX_REFERRAL_DEPTH);
+        let contract = self.contract_mut();
+        let treasury = contract.fee_account.clone().expect("ERR_NO_FEE_ACCOUNT");
+        require!(!contract.frozen.contains(&treasury), "ERR_FROZEN");
+        for referrer in &referrers[..depth] {
+            contract.assert_can_receive(referrer);
+        }
+        let credited = self
+            .internal_deposit(account_id.clone(), env::attached_deposit(), 0)
+            .amount
+            .0;
+
+        let contract = self.contract_mut();
+        contract.referral_depths.insert(account_id, depth as u8);
+        for (level, referrer) in referrers[..depth].iter().enumerate() {
+            let available = contract.funders.get(&treasury).copied().unwrap_or(0);
+            let room = match contract.limits.max_per_account {
+                Some(max_per_account) => {
+                    let balance = contract.funders.get(referrer).copied().unwrap_or(0);
+                    max_per_account.0.saturating_sub(balance)
+                }
+                None => Balance::MAX,
+            };
+            let bonus = (credited * REFERRAL_BONUS_BPS[level] as Balance / 10_000)
+                .min(available)
+                .min(room);
+            if bonus == 0 {
+                continue;
+            }
+            contract.debit(&treasury, bonus);
+            contract.admit_funder(referrer);
+            contract.credit(referrer, bonus);
+            contract.emit_event(
+                "referral_bonus",
+                json!({
+                    "referrer": referrer,
+                    "level": level + 1,
+                    "amount": U128(bonus),
+                }),
+            );
         }
     }
 
-    pub fn get_deposit(&self, account_id: &AccountId) -> Option<&Balance> {
-        self.funders().get(account_id)
+    /// length of the referral chain the account deposited with, if any
+    pub fn referral_depth(&self, account_id: AccountId) -> Option<u8> {
+        self.latest()
+            .and_then(|contract| contract.referral_depths.get(&account_id).copied())
     }
 
-    pub fn add_hash(&mut self, k: String) {
-        match self {
-            Self::V0(_) => {
-                let hashes = self.hashes_mut();
-                hashes.insert(
-                    k,
-                    VersionedBalances::V0(Balances {
-                        deposited: 1,
-                        total: 1,
-                    }),
-                );
+    /// Re-emits the deposit events of a window of `recent_deposits` with their original data and
+    /// event index, for indexers that need to backfill missed events.
+    pub fn reemit_recent(&self, from_index: u64, limit: u64) {
+        require!(
+            self.can_administer(env::predecessor_account_id()),
+            "ERR_NOT_OWNER"
+        );
+        let contract = match self.latest() {
+            Some(contract) => contract,
+            None => return,
+        };
+        for record in contract
+            .recent_deposits
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+        {
+            events::emit(
+                &contract.event_prefix,
+                "deposit",
+                json!({
+                    "account_id": record.account_id,
+                    "amount": U128(record.amount),
+                    "fee": U128(record.fee),
+                    "new_total": U128(record.new_total),
+                    "event_index": record.event_index,
+                }),
+            );
+        }
+    }
+
+    /// Emits a `state_snapshot` event with a window of funders, so an indexer can rebuild the
+    /// balances from logs alone. Returns how many funders are left after the window.
+    pub fn emit_full_state(&self, from_index: u64, limit: u64) -> u64 {
+        require!(
+            self.can_administer(env::predecessor_account_id()),
+            "ERR_NOT_OWNER"
+        );
+        let funders = self.get_funders(from_index, limit);
+        let remaining = self
+            .num_funders()
+            .saturating_sub(from_index + funders.len() as u64);
+        events::emit(
+            &self.get_event_prefix(),
+            "state_snapshot",
+            json!({
+                "from_index": from_index,
+                "funders": funders,
+                "remaining": remaining,
+            }),
+        );
+        remaining
+    }
+
+    /// sends `amount` of the caller's balance back to the caller, `amount` can't be zero
+    pub fn withdraw(&mut self, amount: U128) {
+        require!(amount.0 > 0, "ERR_ZERO_AMOUNT");
+        let account_id = env::predecessor_account_id();
+        let contract = self.contract_mut();
+        require!(!contract.withdrawals_paused, "ERR_WITHDRAWALS_PAUSED");
+        require!(!contract.goal_reached, "ERR_GOAL_REACHED");
+        require!(!contract.frozen.contains(&account_id), "ERR_FROZEN");
+        contract.upgrade_funder(&account_id);
+        contract.debit(&account_id, amount.0);
+        contract.total_withdrawn += amount.0;
+        contract.emit_event(
+            "withdraw",
+            json!({
+                "account_id": account_id,
+                "amount": amount,
+            }),
+        );
+        *contract
+            .pending_withdrawals
+            .entry(account_id.clone())
+            .or_default() += 1;
+        let callback_gas = contract.callback_gas;
+        Promise::new(account_id.clone()).transfer(amount.0).then(
+            Promise::new(env::current_account_id()).function_call(
+                "on_withdraw_complete".to_string(),
+                json!({ "account_id": account_id, "amount": amount })
+                    .to_string()
+                    .into_bytes(),
+                0,
+                callback_gas,
+            ),
+        );
+    }
+
+    /// Moves `amount` of the caller's balance to the balance of `receiver_id`. The receiver has
+    /// to be allowed to receive deposits and stays within `max_funders` and `max_per_account`.
+    pub fn transfer_deposit(&mut self, receiver_id: AccountId, amount: U128) {
+        let account_id = env::predecessor_account_id();
+        let contract = self.contract_mut();
+        require!(!contract.frozen.contains(&account_id), "ERR_FROZEN");
+        contract.assert_can_receive(&receiver_id);
+        if !contract.funders.contains_key(&receiver_id) {
+            require!(
+                contract.funder_slots_remaining() > 0,
+                "ERR_MAX_FUNDERS_REACHED"
+            );
+        }
+        contract.upgrade_funder(&account_id);
+        contract.debit(&account_id, amount.0);
+        contract.upgrade_funder(&receiver_id);
+        let balance = contract.credit(&receiver_id, amount.0);
+        if let Some(max_per_account) = contract.limits.max_per_account {
+            require!(balance <= max_per_account.0, "ERR_MAX_PER_ACCOUNT_REACHED");
+        }
+        contract.emit_event(
+            "transfer_deposit",
+            json!({
+                "account_id": account_id,
+                "receiver_id": receiver_id,
+                "amount": amount,
+            }),
+        );
+    }
+
+    /// Keeps the balance of `account` from being withdrawn or transferred, for compliance. The
+    /// balance can still be read and receive deposits.
+    pub fn freeze_funder(&mut self, account: AccountId) {
+        let contract = self.contract_mut();
+        contract.assert_owner();
+        contract.frozen.insert(&account);
+        contract.emit_event("freeze_funder", json!({ "account_id": account }));
+    }
+
+    pub fn unfreeze_funder(&mut self, account: AccountId) {
+        let contract = self.contract_mut();
+        contract.assert_owner();
+        contract.frozen.remove(&account);
+        contract.emit_event("unfreeze_funder", json!({ "account_id": account }));
+    }
+
+    pub fn is_frozen(&self, account_id: AccountId) -> bool {
+        self.latest()
+            .map_or(false, |contract| contract.frozen.contains(&account_id))
+    }
+
+    /// Callback of `withdraw`, credits the amount back if the transfer failed. Returns whether
+    /// the transfer succeeded.
+    #[private]
+    pub fn on_withdraw_complete(&mut self, account_id: AccountId, amount: U128) -> bool {
+        let succeeded = matches!(env::promise_result(0), PromiseResult::Successful(_));
+        let contract = self.contract_mut();
+        match contract.pending_withdrawals.get(&account_id).copied() {
+            Some(pending) if pending > 1 => {
+                contract
+                    .pending_withdrawals
+                    .insert(account_id.clone(), pending - 1);
+            }
+            _ => {
+                contract.pending_withdrawals.remove(&account_id);
             }
-            _ => unimplemented!(),
         }
+        if !succeeded {
+            contract.total_withdrawn -= amount.0;
+            // The amount was the account's balance before the withdrawal, so it's put back
+            // without checking `max_per_account` or `max_funders`: a cap lowered in between
+            // must not make the account lose the transfer that bounced.
+            contract.credit(&account_id, amount.0);
+            contract.emit_event(
+                "withdraw_failed",
+                json!({
+                    "account_id": account_id,
+                    "amount": amount,
+                }),
+            );
+        }
+        succeeded
     }
 
-    pub fn get_balance(&self, k: String) -> BalancesV1 {
-        let versioned_option = self.hashes().get(&k).expect("ERR_INVALID_KEY");
-        let versioned = if versioned_option.need_upgrade() {
-            // returns upgraded VersionedBalances
-            versioned_option.upgrade()
-        } else {
-            // no upgrade required
-            versioned_option.clone()
-        };
+    /// whether a withdrawal of the account still waits for its callback, so UIs can hold off
+    /// further withdrawals until it reconciles
+    pub fn has_pending_withdrawal(&self, account_id: AccountId) -> bool {
+        self.latest().map_or(false, |contract| {
+            contract.pending_withdrawals.contains_key(&account_id)
+        })
+    }
 
-        versioned.get_balance()
+    /// gas attached to the callback of each withdrawal
+    pub fn get_callback_gas(&self) -> Gas {
+        self.latest()
+            .map_or(CALLBACK_GAS, |contract| contract.callback_gas)
     }
-}
 
-#[cfg(not(target_arch = "wasm32"))]
-#[cfg(test)]
-mod tests {
-    use std::collections::HashMap;
+    /// Lets operators give the withdrawal callback more gas if its logic grows. It can't go below
+    /// the default `CALLBACK_GAS`, which the callback needs to credit failed transfers back.
+    pub fn set_callback_gas(&mut self, gas: Gas) {
+        require!(
+            gas >= CALLBACK_GAS && gas <= MAX_CALLBACK_GAS,
+            "ERR_INVALID_CALLBACK_GAS"
+        );
+        let contract = self.contract_mut();
+        contract.assert_owner();
+        contract.callback_gas = gas;
+    }
 
-    use super::*;
-    use near_sdk::test_utils::test_env::{alice, bob};
-    use near_sdk::test_utils::VMContextBuilder;
-    use near_sdk::testing_env;
+    /// balance `account_id` would have left after withdrawing `amount`, `None` if it can't
+    pub fn balance_after_withdraw(&self, account_id: AccountId, amount: U128) -> Option<U128> {
+        self.funders()
+            .get(&account_id)
+            .and_then(|balance| balance.checked_sub(amount.0))
+            .map(U128)
+    }
 
-    fn set_predecessor_and_deposit(predecessor: AccountId, deposit: Balance) {
-        testing_env!(VMContextBuilder::new()
-            .predecessor_account_id(predecessor)
-            .attached_deposit(deposit)
-            .build())
+    /// when enabled, only allowlisted accounts can deposit or be credited deposits
+    pub fn set_allowlist_enabled(&mut self, enabled: bool) {
+        let contract = self.contract_mut();
+        contract.assert_owner();
+        contract.allowlist_enabled = enabled;
     }
 
-    #[test]
-    fn basic() {
-        let mut contract = VersionedContract::default();
-        set_predecessor_and_deposit(bob(), 8);
-        contract.deposit();
+    pub fn get_allowlist_enabled(&self) -> bool {
+        self.latest()
+            .map_or(false, |contract| contract.allowlist_enabled)
+    }
 
-        set_predecessor_and_deposit(alice(), 10);
-        contract.deposit();
+    pub fn allow(&mut self, account_id: AccountId) {
+        let contract = self.contract_mut();
+        contract.assert_owner();
+        contract.allowlist.insert(&account_id);
+    }
 
-        set_predecessor_and_deposit(bob(), 20);
-        contract.deposit();
+    pub fn disallow(&mut self, account_id: AccountId) {
+        let contract = self.contract_mut();
+        contract.assert_owner();
+        contract.allowlist.remove(&account_id);
+    }
 
-        assert_eq!(contract.get_deposit(&alice()), Some(&10));
-        assert_eq!(contract.get_deposit(&bob()), Some(&28));
-        assert_eq!(contract.get_nonce(), 3);
+    pub fn is_allowlisted(&self, account_id: AccountId) -> bool {
+        self.latest()
+            .map_or(false, |contract| contract.allowlist.contains(&account_id))
     }
 
-    #[test]
-    fn with_upgrade() {
-        let mut contract = {
-            let mut funders = UnorderedMap::new(b"f");
-            funders.insert(bob(), 8);
-            let hashes: HashMap<String, VersionedBalances> = HashMap::new();
+    /// freezes withdrawals, deposits are still accepted
+    pub fn pause_withdrawals(&mut self) {
+        let contract = self.contract_mut();
+        contract.assert_owner();
+        contract.withdrawals_paused = true;
+    }
 
-            VersionedContract::V0(ContractV0 { funders, hashes })
-        };
-        assert_eq!(contract.get_nonce(), 0);
-        assert!(matches!(contract, VersionedContract::V0(_)));
+    pub fn unpause_withdrawals(&mut self) {
+        let contract = self.contract_mut();
+        contract.assert_owner();
+        contract.withdrawals_paused = false;
+    }
 
-        // upgrade on-fly
-        let k = "some_key".to_string();
-        contract.add_hash(k.clone());
-        assert_eq!(
-            contract.get_balance(k),
-            BalancesV1 {
-                deposited: 1,
-                total: 1,
-                earned: 0
+    /// Applies `ops` in order. Every op is validated before the first one is applied, so either
+    /// the whole batch takes effect or none of it does.
+    pub fn batch_admin(&mut self, ops: Vec<AdminOp>) {
+        require!(ops.len() <= MAX_BATCH_SIZE, "ERR_BATCH_TOO_LARGE");
+        let contract = self.contract_mut();
+        contract.assert_owner();
+
+        let mut max_total = contract.limits.max_total;
+        for op in &ops {
+            contract.validate_admin_op(op, max_total);
+            if let AdminOp::SetMaxTotal(new_max_total) = op {
+                max_total = *new_max_total;
             }
+        }
+        let count = ops.len();
+        for op in ops {
+            contract.apply_admin_op(op);
+        }
+        contract.emit_event("batch_admin", json!({ "ops": count }));
+    }
+
+    pub fn is_banned(&self, account_id: AccountId) -> bool {
+        self.latest()
+            .map_or(false, |contract| contract.banned.contains(&account_id))
+    }
+
+    pub fn get_withdrawals_paused(&self) -> bool {
+        self.latest()
+            .map_or(false, |contract| contract.withdrawals_paused)
+    }
+
+    /// Holds the attached deposit in a new `hashes` record under `k`, until the owner or the
+    /// depositor releases it to `release_to`. `k` has to be a key of the depositor, see
+    /// `derive_key`, so escrows never take the place of an account's registration record.
+    #[payable]
+    pub fn deposit_escrow(&mut self, k: String, release_to: AccountId) {
+        let depositor = env::predecessor_account_id();
+        let amount = env::attached_deposit();
+        require!(amount > 0, "ERR_ZERO_DEPOSIT");
+        require!(is_own_key(&depositor, &k), "ERR_NOT_KEY_OWNER");
+
+        let contract = self.contract_mut();
+        contract.assert_deposit_allowed(&depositor, &release_to);
+        require!(!contract.frozen.contains(&depositor), "ERR_FROZEN");
+        require!(!contract.hashes.contains_key(&k), "ERR_KEY_EXISTS");
+        contract
+            .own_deposit_at
+            .insert(depositor.clone(), env::block_timestamp());
+        contract.hashes.insert(
+            k.clone(),
+            VersionedBalances::V2(BalancesV2 {
+                deposited: amount,
+                total: amount,
+                earned: 0,
+                updated_at: env::block_timestamp(),
+                deleted: false,
+            }),
         );
-        // end
+        contract.escrows.insert(
+            k.clone(),
+            Escrow {
+                depositor: depositor.clone(),
+                release_to: release_to.clone(),
+                amount,
+            },
+        );
+        contract.emit_event(
+            "escrow_deposit",
+            json!({
+                "key": k,
+                "account_id": depositor,
+                "release_to": release_to,
+                "amount": U128(amount),
+            }),
+        );
+    }
 
-        set_predecessor_and_deposit(alice(), 1000);
-        contract.deposit();
+    /// Sends the escrow under `k` to its `release_to` account. The escrow and its record are
+    /// only removed once the transfer succeeded, a failed transfer leaves them in place so the
+    /// release can be retried.
+    pub fn release_escrow(&mut self, k: String) {
+        let caller = env::predecessor_account_id();
+        let contract = self.contract_mut();
+        let escrow = contract.escrows.get(&k).cloned().expect("ERR_NOT_ESCROW");
+        require!(
+            caller == escrow.depositor || contract.is_admin(&caller),
+            "ERR_NOT_ALLOWED"
+        );
+        require!(contract.releasing_escrows.insert(&k), "ERR_RELEASE_PENDING");
+        let callback_gas = contract.callback_gas;
+        Promise::new(escrow.release_to)
+            .transfer(escrow.amount)
+            .then(Promise::new(env::current_account_id()).function_call(
+                "on_escrow_released".to_string(),
+                json!({ "k": k }).to_string().into_bytes(),
+                0,
+                callback_gas,
+            ));
+    }
 
-        assert!(matches!(contract, VersionedContract::V1(_)));
-        assert_eq!(contract.get_nonce(), 1);
-        assert_eq!(contract.get_deposit(&alice()), Some(&1000));
-        assert_eq!(contract.get_deposit(&bob()), Some(&8));
+    /// Callback of `release_escrow`, removes the escrow and its record if the transfer
+    /// succeeded. Returns whether it succeeded.
+    #[private]
+    pub fn on_escrow_released(&mut self, k: String) -> bool {
+        let succeeded = matches!(env::promise_result(0), PromiseResult::Successful(_));
+        let contract = self.contract_mut();
+        contract.releasing_escrows.remove(&k);
+        let escrow = contract.escrows.get(&k).cloned().expect("ERR_NOT_ESCROW");
+        if succeeded {
+            contract.escrows.remove(&k);
+            contract.hashes.remove(&k);
+        }
+        contract.emit_event(
+            if succeeded {
+                "escrow_release"
+            } else {
+                "escrow_release_failed"
+            },
+            json!({
+                "key": k,
+                "release_to": escrow.release_to,
+                "amount": U128(escrow.amount),
+            }),
+        );
+        succeeded
+    }
+
+    pub fn get_escrow(&self, k: String) -> Option<Escrow> {
+        self.latest()
+            .and_then(|contract| contract.escrows.get(&k).cloned())
+    }
+
+    /// sets the caller's profile note, at most `MAX_PROFILE_LENGTH` bytes
+    pub fn set_profile(&mut self, note: String) {
+        require!(note.len() <= MAX_PROFILE_LENGTH, "ERR_PROFILE_TOO_LONG");
+        let account_id = env::predecessor_account_id();
+        self.contract_mut().profiles.insert(account_id, note);
+    }
+
+    pub fn get_profile(&self, account_id: AccountId) -> Option<String> {
+        self.latest()
+            .and_then(|contract| contract.profiles.get(&account_id).cloned())
+    }
+
+    /// account, amount and timestamp of the biggest deposit in `recent_deposits`
+    pub fn largest_recent_deposit(&self) -> Option<(AccountId, U128, u64)> {
+        self.latest()?
+            .recent_deposits
+            .iter()
+            .max_by_key(|record| record.amount)
+            .map(|record| {
+                (
+                    record.account_id.clone(),
+                    U128(record.amount),
+                    record.timestamp,
+                )
+            })
+    }
+
+    pub fn get_nonce(&self) -> u64 {
+        match self {
+            Self::V0(_) => 0,
+            Self::V1(contract) => contract.nonce,
+            Self::V2(contract) => contract.nonce,
+        }
+    }
+
+    /// version of the state layout currently stored
+    pub fn state_version(&self) -> u8 {
+        match self {
+            Self::V0(_) => 0,
+            Self::V1(_) => 1,
+            Self::V2(_) => 2,
+        }
+    }
+
+    /// storage used by the contract account, in bytes
+    pub fn approx_state_bytes(&self) -> u64 {
+        env::storage_usage()
+    }
+
+    /// NEAR locked as storage staking for the current state, it can't be withdrawn from the
+    /// contract balance
+    pub fn locked_storage_cost(&self) -> U128 {
+        U128(env::storage_usage() as Balance * env::storage_byte_cost())
+    }
+
+    /// Smallest deposit a brand-new funder should attach: the largest of `min_deposit` and the
+    /// storage staking of a new funder entry, so wallets can prefill a safe amount.
+    pub fn effective_min_first_deposit(&self) -> U128 {
+        let storage_cost = FUNDER_STORAGE_BYTES as Balance * env::storage_byte_cost();
+        let min_deposit = self
+            .limits()
+            .min_deposit
+            .map_or(0, |min_deposit| min_deposit.0);
+        U128(storage_cost.max(min_deposit))
+    }
+
+    /// number of state migrations applied since the contract was deployed
+    pub fn migration_count(&self) -> u64 {
+        self.latest().map_or(0, |contract| contract.migrations)
+    }
+
+    /// `(from_version, to_version, block_timestamp)` of the latest state upgrades, oldest first
+    pub fn upgrade_history(&self) -> Vec<(u8, u8, u64)> {
+        self.latest()
+            .map_or_else(Vec::new, |contract| contract.upgrade_log.clone())
+    }
+
+    /// version of the deployed code, see `state_version` for the shape of the stored state
+    pub fn contract_version(&self) -> String {
+        CONTRACT_VERSION.to_string()
+    }
+
+    /// `(standard, version)` of the NEP standards implemented, e.g. `("nep297", "1.0.0")`
+    pub fn supported_standards(&self) -> Vec<(String, String)> {
+        SUPPORTED_STANDARDS
+            .iter()
+            .map(|(standard, version)| (standard.to_string(), version.to_string()))
+            .collect()
+    }
+
+    /// state versions this contract can read and upgrade from
+    pub fn known_versions(&self) -> Vec<u8> {
+        (0..=LATEST_STATE_VERSION).collect()
+    }
+
+    /// `VersionedBalances` versions this contract can read and upgrade from
+    pub fn known_balance_versions(&self) -> Vec<u8> {
+        (0..=LATEST_BALANCES_VERSION).collect()
+    }
+
+    /// number of events emitted so far, the next event is emitted with this index
+    pub fn event_index(&self) -> u64 {
+        self.latest().map_or(0, |contract| contract.event_index)
+    }
+
+    /// Lifetime number of events, for monitors detecting activity at a glance. Re-emitted
+    /// deposits and state snapshots repeat existing data and aren't counted.
+    pub fn total_events(&self) -> u64 {
+        self.event_index()
+    }
+
+    pub fn get_owner(&self) -> AccountId {
+        match self.latest() {
+            Some(contract) => contract.owner_id.clone(),
+            // older states get the contract account as owner when upgraded
+            None => env::current_account_id(),
+        }
+    }
+
+    /// Proposes `new_owner` as the next owner. Ownership only moves once it calls
+    /// `accept_owner`, so a mistyped account can't take over or lock the contract.
+    pub fn propose_owner(&mut self, new_owner: AccountId) {
+        assert_valid_owner(&new_owner);
+        let contract = self.contract_mut();
+        contract.assert_strict_owner();
+        contract.pending_owner = Some(new_owner.clone());
+        contract.emit_event("propose_owner", json!({ "pending_owner": new_owner }));
+    }
+
+    /// makes the proposed owner, who has to be the caller, the owner
+    pub fn accept_owner(&mut self) {
+        let account_id = env::predecessor_account_id();
+        let contract = self.contract_mut();
+        require!(
+            contract.pending_owner.as_ref() == Some(&account_id),
+            "ERR_NOT_PENDING_OWNER"
+        );
+        contract.pending_owner = None;
+        let old_owner = std::mem::replace(&mut contract.owner_id, account_id.clone());
+        contract.emit_event(
+            "owner_changed",
+            json!({
+                "old_owner": old_owner,
+                "new_owner": account_id,
+            }),
+        );
+    }
+
+    /// revokes a pending ownership proposal before it is accepted
+    pub fn cancel_ownership_transfer(&mut self) {
+        let contract = self.contract_mut();
+        contract.assert_strict_owner();
+        if let Some(pending_owner) = contract.pending_owner.take() {
+            contract.emit_event(
+                "cancel_ownership_transfer",
+                json!({ "pending_owner": pending_owner }),
+            );
+        }
+    }
+
+    /// Lets `account` call the admin methods until the block timestamp `until_ts`, for
+    /// time-boxed operations. The delegate can't change the owner or the delegation.
+    pub fn delegate_admin(&mut self, account: AccountId, until_ts: u64) {
+        let contract = self.contract_mut();
+        contract.assert_strict_owner();
+        contract.delegate = Some((account.clone(), until_ts));
+        contract.emit_event(
+            "delegate_admin",
+            json!({
+                "account_id": account,
+                "until_ts": until_ts,
+            }),
+        );
+    }
+
+    pub fn revoke_delegate(&mut self) {
+        let contract = self.contract_mut();
+        contract.assert_strict_owner();
+        if let Some((account, _)) = contract.delegate.take() {
+            contract.emit_event("revoke_delegate", json!({ "account_id": account }));
+        }
+    }
+
+    pub fn get_pending_owner(&self) -> Option<AccountId> {
+        self.latest()
+            .and_then(|contract| contract.pending_owner.clone())
+    }
+
+    /// whether `account_id` is allowed to call the admin methods
+    pub fn can_administer(&self, account_id: AccountId) -> bool {
+        match self.latest() {
+            Some(contract) => contract.is_admin(&account_id),
+            None => account_id == self.get_owner(),
+        }
+    }
+
+    /// parameters the contract state was created with, `None` before the upgrade to v2
+    pub fn init_params(&self) -> Option<InitParams> {
+        self.latest().map(|contract| contract.init_params.clone())
+    }
+
+    pub fn upgrade_policy(&self) -> UpgradePolicy {
+        match self.latest() {
+            Some(contract) => UpgradePolicy {
+                code_upgrades_allowed: contract.code_upgrades_allowed,
+                upgrader_id: contract.upgrader_id.clone(),
+                automatic_migration: true,
+            },
+            None => UpgradePolicy::default(),
+        }
+    }
+
+    /// sets whether `deploy_code` can deploy new code and who can call it, the owner when
+    /// `upgrader_id` is unset
+    pub fn set_upgrade_policy(
+        &mut self,
+        code_upgrades_allowed: bool,
+        upgrader_id: Option<AccountId>,
+    ) {
+        let contract = self.contract_mut();
+        contract.assert_strict_owner();
+        contract.code_upgrades_allowed = code_upgrades_allowed;
+        contract.upgrader_id = upgrader_id;
+    }
+
+    pub fn get_event_prefix(&self) -> String {
+        self.latest().map_or_else(
+            || events::DEFAULT_EVENT_PREFIX.to_string(),
+            |contract| contract.event_prefix.clone(),
+        )
+    }
+
+    /// Prepends `prefix` to the `standard` of all events emitted from now on. The prefix is at
+    /// most `MAX_EVENT_PREFIX_LENGTH` bytes of ASCII letters, digits, `-`, `_` and `.`.
+    pub fn set_event_prefix(&mut self, prefix: String) {
+        let contract = self.contract_mut();
+        contract.assert_owner();
+        require!(
+            prefix.len() <= MAX_EVENT_PREFIX_LENGTH
+                && prefix
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')),
+            "ERR_INVALID_EVENT_PREFIX"
+        );
+        contract.event_prefix = prefix;
+    }
+
+    pub fn get_lenient_reads(&self) -> bool {
+        self.latest()
+            .map_or(false, |contract| contract.lenient_reads)
+    }
+
+    /// when enabled, reads of missing entries return nothing instead of aborting the call
+    pub fn set_lenient_reads(&mut self, lenient: bool) {
+        let contract = self.contract_mut();
+        contract.assert_owner();
+        contract.lenient_reads = lenient;
+    }
+
+    pub fn get_require_registration(&self) -> bool {
+        self.latest()
+            .map_or(false, |contract| contract.require_registration)
+    }
+
+    /// when enabled, accounts need an `add_hash` record keyed by their account id to deposit or
+    /// be credited deposits
+    pub fn set_require_registration(&mut self, required: bool) {
+        let contract = self.contract_mut();
+        contract.assert_owner();
+        contract.require_registration = required;
+    }
+
+    /// sum of all funder balances
+    pub fn total_deposited(&self) -> U128 {
+        match self.latest() {
+            Some(contract) => U128(contract.total_deposited),
+            None => U128(self.funders().values().sum()),
+        }
+    }
+
+    /// Whether the cached `total_deposited` matches the sum of the funder balances. The sum is
+    /// only computed for at most `MAX_CONSISTENCY_CHECK_SIZE` funders, larger contracts and
+    /// states without a cached total are reported as consistent.
+    pub fn total_cache_consistent(&self) -> bool {
+        match self.latest() {
+            Some(contract) if self.num_funders() <= MAX_CONSISTENCY_CHECK_SIZE => {
+                contract.funders.values().sum::<Balance>() == contract.total_deposited
+            }
+            _ => true,
+        }
+    }
+
+    pub fn num_funders(&self) -> u64 {
+        self.funders().len() as u64
+    }
+
+    pub fn num_hashes(&self) -> u64 {
+        self.hashes().len() as u64
+    }
+
+    /// whether the contract has neither funders nor `hashes` records
+    pub fn is_empty(&self) -> bool {
+        self.num_funders() == 0 && self.num_hashes() == 0
+    }
+
+    /// average funder balance, from the cached total
+    pub fn average_deposit(&self) -> U128 {
+        match self.num_funders() {
+            0 => U128(0),
+            num_funders => U128(self.total_deposited().0 / num_funders as Balance),
+        }
+    }
+
+    /// sum of the deposits accepted in the current block
+    pub fn deposits_this_block(&self) -> U128 {
+        match self.latest() {
+            Some(contract) if contract.current_block == env::block_height() => {
+                U128(contract.current_block_deposits)
+            }
+            _ => U128(0),
+        }
+    }
+
+    /// lifetime sum of all withdrawals
+    pub fn total_withdrawn(&self) -> U128 {
+        U128(self.latest().map_or(0, |contract| contract.total_withdrawn))
+    }
+
+    pub fn get_max_total(&self) -> Option<U128> {
+        self.latest().and_then(|contract| contract.limits.max_total)
+    }
+
+    /// caps `total_deposited`, the part of a deposit above it is refunded
+    pub fn set_max_total(&mut self, max_total: Option<U128>) {
+        let contract = self.contract_mut();
+        contract.assert_owner();
+        contract.limits.max_total = max_total;
+    }
+
+    /// caps the balance of a single account, the part of a deposit above it is refunded
+    pub fn set_max_per_account(&mut self, max_per_account: Option<U128>) {
+        let contract = self.contract_mut();
+        contract.assert_owner();
+        contract.limits.max_per_account = max_per_account;
+    }
+
+    /// `(credited, refunded)` of a deposit of `amount` by `account_id` under the current caps
+    /// and fees, for showing the net effect before sending it
+    pub fn compute_change(&self, account_id: AccountId, amount: U128) -> (U128, U128) {
+        let plan = match self.latest() {
+            Some(contract) => contract.plan_deposit_for(&account_id, amount.0),
+            None => Contract::default().plan_deposit(amount.0),
+        };
+        (U128(plan.credited), U128(plan.refund))
+    }
+
+    /// makes accounts wait `cooldown` seconds between deposits, `None` removes the cooldown
+    pub fn set_deposit_cooldown(&mut self, cooldown: Option<u64>) {
+        let contract = self.contract_mut();
+        contract.assert_owner();
+        contract.limits.deposit_cooldown = cooldown;
+    }
+
+    /// seconds left before the account can deposit again, 0 when it can deposit now
+    pub fn deposit_cooldown_remaining(&self, account_id: AccountId) -> u64 {
+        self.latest().map_or(0, |contract| {
+            contract.deposit_cooldown_remaining(&account_id)
+        })
+    }
+
+    /// caps the number of funders, `None` removes the cap
+    pub fn set_max_funders(&mut self, max_funders: Option<u64>) {
+        let contract = self.contract_mut();
+        contract.assert_owner();
+        contract.limits.max_funders = max_funders;
+    }
+
+    /// Funders that can still be added before `max_funders` is reached, for warning that
+    /// registration is nearly closed. `u64::MAX` when there is no cap.
+    pub fn funder_slots_remaining(&self) -> u64 {
+        match self.latest() {
+            Some(contract) => contract.funder_slots_remaining(),
+            None => u64::MAX,
+        }
+    }
+
+    /// funding goal and whether it was reached, withdrawals are locked once it is
+    pub fn goal_status(&self) -> (U128, bool) {
+        self.latest().map_or((U128(0), false), |contract| {
+            (U128(contract.goal), contract.goal_reached)
+        })
+    }
+
+    /// sets the funding goal, 0 removes it. A reached goal stays reached.
+    pub fn set_goal(&mut self, goal: U128) {
+        let contract = self.contract_mut();
+        contract.assert_owner();
+        contract.goal = goal.0;
+        contract.update_goal_reached();
+    }
+
+    /// all configurable bounds of the contract
+    pub fn limits(&self) -> Limits {
+        self.latest()
+            .map_or_else(Limits::default, |contract| contract.limits.clone())
+    }
+
+    /// all settings in a single struct, as returned by the individual getters
+    pub fn config(&self) -> Config {
+        Config {
+            owner_id: self.get_owner(),
+            limits: self.limits(),
+            goal: self.goal_status().0,
+            fee_bps: self.get_fee_bps(),
+            fee_account: self.fee_account(),
+            fee_recipients: self.fee_recipients(),
+            fee_round_up: self.get_fee_round_up(),
+            require_registration: self.get_require_registration(),
+            allowlist_enabled: self.get_allowlist_enabled(),
+            withdrawals_paused: self.get_withdrawals_paused(),
+            lenient_reads: self.get_lenient_reads(),
+            upgrade_policy: self.upgrade_policy(),
+            event_prefix: self.get_event_prefix(),
+            callback_gas: self.get_callback_gas(),
+        }
+    }
+
+    /// restores every configurable bound to its default, to recover from a misconfiguration
+    pub fn reset_limits_to_default(&mut self) {
+        let contract = self.contract_mut();
+        contract.assert_owner();
+        contract.limits = Limits::default();
+        contract.emit_event("reset_limits", json!({ "limits": contract.limits }));
+    }
+
+    pub fn get_fee_bps(&self) -> u16 {
+        self.latest().map_or(0, |contract| contract.fee_bps)
+    }
+
+    /// takes `fee_bps` basis points of each deposit and credits them to `fee_account`
+    pub fn set_fee(&mut self, fee_bps: u16, fee_account: Option<AccountId>) {
+        require!(fee_bps <= 10_000, "ERR_INVALID_FEE");
+        let contract = self.contract_mut();
+        contract.assert_owner();
+        contract.fee_bps = fee_bps;
+        contract.fee_account = fee_account;
+    }
+
+    /// Splits the deposit fees between `recipients` by basis points, which have to add up to
+    /// 10_000. An empty list credits the fees to `fee_account` again.
+    pub fn set_fee_recipients(&mut self, recipients: Vec<(AccountId, u16)>) {
+        require!(recipients.len() <= MAX_BATCH_SIZE, "ERR_BATCH_TOO_LARGE");
+        require!(
+            recipients.is_empty()
+                || recipients.iter().map(|(_, bps)| *bps as u32).sum::<u32>() == 10_000,
+            "ERR_INVALID_FEE_SPLIT"
+        );
+        let contract = self.contract_mut();
+        contract.assert_owner();
+        contract.fee_recipients = recipients;
+    }
+
+    pub fn fee_recipients(&self) -> Vec<(AccountId, u16)> {
+        self.latest()
+            .map_or_else(Vec::new, |contract| contract.fee_recipients.clone())
+    }
+
+    /// fee collected on a deposit of `amount` under the current fee settings and `max_total`
+    pub fn fee_preview(&self, amount: U128) -> U128 {
+        U128(
+            self.latest()
+                .map_or(0, |contract| contract.plan_deposit(amount.0).fee),
+        )
+    }
+
+    /// account the deposit fees are credited to, if any
+    pub fn fee_account(&self) -> Option<AccountId> {
+        self.latest()
+            .and_then(|contract| contract.fee_account.clone())
+    }
+
+    pub fn get_fee_round_up(&self) -> bool {
+        self.latest()
+            .map_or(false, |contract| contract.fee_round_up)
+    }
+
+    /// rounds fees half up instead of truncating them
+    pub fn set_fee_round_up(&mut self, round_up: bool) {
+        let contract = self.contract_mut();
+        contract.assert_owner();
+        contract.fee_round_up = round_up;
+    }
+
+    pub fn get_deposit(&self, account_id: &AccountId) -> Option<&Balance> {
+        self.funders().get(account_id)
+    }
+
+    /// whether the balance of `account_id` equals `claimed`, for reconciliation checks by
+    /// off-chain systems. Accounts that aren't funders have a balance of 0.
+    pub fn balance_matches(&self, account_id: AccountId, claimed: U128) -> bool {
+        self.funders().get(&account_id).copied().unwrap_or(0) == claimed.0
+    }
+
+    pub fn is_funder(&self, account_id: AccountId) -> bool {
+        self.funders().contains_key(&account_id)
+    }
+
+    /// key of the `hashes` record `account_id` keeps under `salt`
+    pub fn derive_key(&self, account_id: AccountId, salt: String) -> String {
+        derive_key(&account_id, &salt)
+    }
+
+    /// whether `account_id` has a `hashes` record under `salt`
+    pub fn has_record(&self, account_id: AccountId, salt: String) -> bool {
+        self.hashes().contains_key(&derive_key(&account_id, &salt))
+    }
+
+    /// signed difference between the balances of `a` and `b`, missing accounts count as 0
+    pub fn balance_diff(&self, a: AccountId, b: AccountId) -> I128 {
+        let balance = |account_id: AccountId| self.funders().get(&account_id).copied().unwrap_or(0);
+        I128(balance(a) as i128 - balance(b) as i128)
+    }
+
+    /// `is_funder` for up to `MAX_BATCH_SIZE` accounts, in the given order
+    pub fn are_funders(&self, account_ids: Vec<AccountId>) -> Vec<bool> {
+        require!(account_ids.len() <= MAX_BATCH_SIZE, "ERR_BATCH_TOO_LARGE");
+        account_ids
+            .into_iter()
+            .map(|account_id| self.is_funder(account_id))
+            .collect()
+    }
+
+    /// sum of the balances of up to `MAX_BATCH_SIZE` accounts, unknown accounts count as 0
+    pub fn group_total(&self, account_ids: Vec<AccountId>) -> U128 {
+        require!(account_ids.len() <= MAX_BATCH_SIZE, "ERR_BATCH_TOO_LARGE");
+        U128(
+            account_ids
+                .iter()
+                .filter_map(|account_id| self.funders().get(account_id))
+                .sum(),
+        )
+    }
+
+    pub fn get_funders(&self, from_index: u64, limit: u64) -> Vec<(AccountId, U128)> {
+        self.funders()
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .map(|(account_id, balance)| (account_id.clone(), U128(*balance)))
+            .collect()
+    }
+
+    /// Funders of a window whose latest deposit is after `ts`. Funders without a known deposit
+    /// time are skipped. O(n) in the window size.
+    pub fn funders_since(&self, ts: u64, from_index: u64, limit: u64) -> Vec<AccountId> {
+        let contract = match self.latest() {
+            Some(contract) => contract,
+            None => return Vec::new(),
+        };
+        contract
+            .funders
+            .keys()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .filter(|account_id| {
+                contract
+                    .funder_info
+                    .get(*account_id)
+                    .and_then(|info| info.last_deposit_at)
+                    .map_or(false, |last_deposit_at| last_deposit_at > ts)
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Share of the other funders with a smaller balance than the account, from 0 for the
+    /// smallest to 100 for the largest. `None` for accounts that aren't funders. O(n) in the
+    /// number of funders.
+    pub fn percentile(&self, account_id: AccountId) -> Option<u8> {
+        let balance = *self.funders().get(&account_id)?;
+        let others = self.num_funders() - 1;
+        if others == 0 {
+            return Some(100);
+        }
+        let smaller = self.funders().values().filter(|&&b| b < balance).count() as u64;
+        Some((smaller * 100 / others) as u8)
+    }
+
+    /// Balances of a window of funders summed by top-level account, e.g. `near` for `bob.near`.
+    /// Top-level and implicit accounts have no suffix and are summed under `""`.
+    pub fn deposits_by_suffix(&self, from_index: u64, limit: u64) -> HashMap<String, U128> {
+        let mut totals: HashMap<String, U128> = HashMap::new();
+        for (account_id, balance) in self
+            .funders()
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+        {
+            let suffix = account_id
+                .as_str()
+                .rsplit_once('.')
+                .map_or("", |(_, suffix)| suffix);
+            totals.entry(suffix.to_string()).or_insert(U128(0)).0 += balance;
+        }
+        totals
+    }
+
+    /// Balance needed to reach `target_rank` on the leaderboard, 1 being the largest balance:
+    /// one more than the balance currently holding that rank. `None` for rank 0 or a rank
+    /// nobody holds. O(n log n) in the number of funders.
+    pub fn deposit_for_rank(&self, target_rank: u64) -> Option<U128> {
+        if target_rank == 0 {
+            return None;
+        }
+        let mut balances: Vec<Balance> = self.funders().values().copied().collect();
+        balances.sort_unstable_by(|a, b| b.cmp(a));
+        balances
+            .get(target_rank as usize - 1)
+            .map(|balance| U128(balance + 1))
+    }
+
+    /// Earliest and latest first deposit time across funders, `None` while no funder has one.
+    /// Funders without a known deposit time are skipped. O(n) in the number of funders.
+    pub fn funder_time_range(&self) -> Option<(u64, u64)> {
+        let contract = self.latest()?;
+        contract
+            .funders
+            .keys()
+            .filter_map(|account_id| contract.funder_info.get(account_id)?.first_deposit_at)
+            .fold(None, |range, ts| match range {
+                None => Some((ts, ts)),
+                Some((min, max)) => Some((min.min(ts), max.max(ts))),
+            })
+    }
+
+    /// Number of funders of a window per balance bucket. `n` ascending edges make `n + 1`
+    /// buckets: below the first edge, from each edge up to the next one, and from the last edge
+    /// up. O(n) in the window size.
+    pub fn deposit_histogram(
+        &self,
+        bucket_edges: Vec<U128>,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<u64> {
+        require!(bucket_edges.len() <= MAX_BATCH_SIZE, "ERR_BATCH_TOO_LARGE");
+        require!(
+            bucket_edges.windows(2).all(|pair| pair[0].0 < pair[1].0),
+            "ERR_INVALID_EDGES"
+        );
+        let mut counts = vec![0; bucket_edges.len() + 1];
+        for balance in self
+            .funders()
+            .values()
+            .skip(from_index as usize)
+            .take(limit as usize)
+        {
+            counts[bucket_edges.partition_point(|edge| edge.0 <= *balance)] += 1;
+        }
+        counts
+    }
+
+    /// Sorted distinct balances of a window of funders. O(n) in the window size.
+    pub fn distinct_amounts(&self, from_index: u64, limit: u64) -> Vec<U128> {
+        self.funders()
+            .values()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .copied()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(U128)
+            .collect()
+    }
+
+    /// borsh-encoded `Vec<(AccountId, Balance)>` of a window of funders
+    pub fn export_snapshot(&self, from_index: u64, limit: u64) -> Base64VecU8 {
+        let snapshot: Vec<(AccountId, Balance)> = self
+            .get_funders(from_index, limit)
+            .into_iter()
+            .map(|(account_id, balance)| (account_id, balance.0))
+            .collect();
+        Base64VecU8(snapshot.try_to_vec().expect("ERR_SERIALIZE"))
+    }
+
+    /// Whether `blob` decodes to exactly the first `MAX_SNAPSHOT_SIZE` funders, i.e. matches
+    /// `export_snapshot(0, MAX_SNAPSHOT_SIZE)`. Funders past that bound can't be verified.
+    pub fn verify_funders_blob(&self, blob: Base64VecU8) -> bool {
+        match Vec::<(AccountId, Balance)>::try_from_slice(&blob.0) {
+            Ok(snapshot) => {
+                snapshot.try_to_vec().ok() == Some(self.export_snapshot(0, MAX_SNAPSHOT_SIZE).0)
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Earned over deposited amounts summed across the `hashes` records, in basis points and
+    /// saturating at `u16::MAX`. 0 while nothing is deposited, soft-deleted records don't count.
+    pub fn overall_yield_bps(&self) -> u16 {
+        let (earned, deposited) = self
+            .hashes()
+            .values()
+            .map(|versioned| versioned.clone().get_balance())
+            .filter(|bal| !bal.deleted)
+            .fold((0, 0), |(earned, deposited), bal| {
+                (earned + bal.earned, deposited + bal.deposited)
+            });
+        match deposited {
+            0 => 0,
+            _ => (earned * 10_000 / deposited).min(u16::MAX as Balance) as u16,
+        }
+    }
+
+    /// versions of the `hashes` records, sorted by key
+    pub fn get_hash_versions(&self, from_index: u64, limit: u64) -> Vec<(String, u8)> {
+        self.hashes_page(from_index, limit)
+            .into_iter()
+            .map(|(k, versioned)| (k.clone(), versioned.version()))
+            .collect()
+    }
+
+    /// funder data, `None` for legacy funders that haven't deposited since the upgrade
+    pub fn get_funder_info(&self, account_id: AccountId) -> Option<FunderInfo> {
+        self.latest()
+            .and_then(|contract| contract.funder_info.get(&account_id).cloned())
+    }
+
+    /// nonce right after the account's latest deposit, `None` for legacy funders and migrated
+    /// funders that haven't deposited since
+    pub fn last_deposit_nonce(&self, account_id: AccountId) -> Option<u64> {
+        self.get_funder_info(account_id)
+            .and_then(|info| info.last_deposit_nonce)
+    }
+
+    /// Balance of the account averaged over the time since it moved to the current funder
+    /// schema, the basis for distributing yield by holding period. Legacy funders have no
+    /// history, their current balance is returned.
+    pub fn twab(&self, account_id: AccountId) -> U128 {
+        let balance = self.funders().get(&account_id).copied().unwrap_or(0);
+        match self.get_funder_info(account_id) {
+            Some(info) => U128(info.twab(balance, env::block_timestamp())),
+            None => U128(balance),
+        }
+    }
+
+    /// Moves a legacy funder to the current funder schema without waiting for its next deposit.
+    /// Legacy entries have no deposit history, so its deposit times and nonce stay unknown until
+    /// its next deposit. Returns whether the funder was upgraded, `false` for unknown or already
+    /// migrated accounts.
+    pub fn migrate_funder(&mut self, account_id: AccountId) -> bool {
+        self.contract_mut().upgrade_funder(&account_id)
+    }
+
+    /// Number of legacy funders moved to the current funder schema so far, to monitor the
+    /// passive migration. Views can't write state, so a legacy funder is upgraded by the first
+    /// deposit or withdrawal touching it, or by `migrate_funder`.
+    pub fn funders_migrated(&self) -> u64 {
+        self.latest()
+            .map_or(0, |contract| contract.funders_migrated)
+    }
+
+    /// number of funders on each funder schema version
+    pub fn funder_version_histogram(&self) -> HashMap<u8, u64> {
+        let mut histogram = HashMap::new();
+        for account_id in self.funders().keys() {
+            let version = match self.latest() {
+                Some(contract) if contract.funder_info.contains_key(account_id) => {
+                    FUNDER_INFO_VERSION
+                }
+                _ => LEGACY_FUNDER_VERSION,
+            };
+            *histogram.entry(version).or_default() += 1;
+        }
+        histogram
+    }
+
+    /// Whether any of the first `scan_limit` `hashes` records still needs an upgrade. This is a
+    /// bounded scan: records beyond the limit aren't checked, so `false` doesn't guarantee the
+    /// migration is complete unless `scan_limit` covers all records.
+    pub fn migration_pending(&self, scan_limit: u64) -> bool {
+        self.hashes()
+            .values()
+            .take(scan_limit as usize)
+            .any(|versioned| versioned.need_upgrade())
+    }
+
+    /// keys of the records needing an upgrade within a window of `hashes`, sorted by key
+    pub fn stale_keys(&self, from_index: u64, limit: u64) -> Vec<String> {
+        self.hashes_page(from_index, limit)
+            .into_iter()
+            .filter(|(_, versioned)| versioned.need_upgrade())
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+
+    /// Estimates the gas needed to upgrade every `hashes` record still on an older version, by
+    /// upgrading up to `sample` of them in memory, without persisting anything, and extrapolating
+    /// the average cost per record. Helps picking a safe `limit` for batched upgrades.
+    pub fn estimate_upgrade_gas(&self, sample: u64) -> U128 {
+        let pending: Vec<_> = self
+            .hashes()
+            .values()
+            .filter(|versioned| versioned.need_upgrade())
+            .collect();
+        let sampled = pending.len().min(sample as usize);
+        if sampled == 0 {
+            return U128(0);
+        }
+
+        let start = env::used_gas();
+        for versioned in &pending[..sampled] {
+            let upgraded = versioned.upgrade();
+            upgraded.try_to_vec().expect("ERR_SERIALIZE");
+        }
+        let used = env::used_gas().0 - start.0;
+        U128(used as u128 / sampled as u128 * pending.len() as u128)
+    }
+
+    /// `get_funders` and `get_hash_versions` for the same window in a single call
+    pub fn audit_page(&self, from_index: u64, limit: u64) -> AuditPage {
+        AuditPage {
+            funders: self.get_funders(from_index, limit),
+            hash_versions: self.get_hash_versions(from_index, limit),
+        }
+    }
+
+    /// Adds a placeholder record under `k`, rejecting keys that already have a record. Accounts
+    /// can only add records under their own keys, see `derive_key`. Other keys, such as the
+    /// account ids registering accounts for `require_registration`, are added by the owner.
+    pub fn add_hash(&mut self, k: String) {
+        let account_id = env::predecessor_account_id();
+        require!(
+            is_own_key(&account_id, &k) || self.can_administer(account_id),
+            "ERR_NOT_KEY_OWNER"
+        );
+        require!(!self.hashes().contains_key(&k), "ERR_KEY_EXISTS");
+        let balances = match self {
+            Self::V0(_) => VersionedBalances::V0(Balances {
+                deposited: 1,
+                total: 1,
+            }),
+            _ => VersionedBalances::V2(BalancesV2 {
+                deposited: 1,
+                total: 1,
+                earned: 0,
+                updated_at: env::block_timestamp(),
+                deleted: false,
+            }),
+        };
+        self.hashes_mut().insert(k, balances);
+    }
+
+    /// Imports balance records during a migration as `V1` records, overwriting existing keys.
+    /// Returns the number of records afterwards.
+    #[private]
+    pub fn seed_hashes(&mut self, entries: Vec<(String, BalancesV1)>) -> u64 {
+        require!(entries.len() <= MAX_BATCH_SIZE, "ERR_BATCH_TOO_LARGE");
+        let hashes = self.hashes_mut();
+        for (k, balance) in entries {
+            hashes.insert(k, VersionedBalances::V1(balance));
+        }
+        self.num_hashes()
+    }
+
+    /// Moves `amount` of the caller's `funders` balance into the `hashes` record under `k`,
+    /// creating the record if it doesn't exist. `k` has to be a key of the caller, see
+    /// `derive_key`, and can't be an escrow or a soft-deleted record.
+    pub fn allocate_to_record(&mut self, k: String, amount: U128) {
+        let account_id = env::predecessor_account_id();
+        require!(is_own_key(&account_id, &k), "ERR_NOT_KEY_OWNER");
+        let contract = self.contract_mut();
+        require!(!contract.frozen.contains(&account_id), "ERR_FROZEN");
+        require!(!contract.escrows.contains_key(&k), "ERR_ESCROW_KEY");
+        require!(
+            !contract
+                .hashes
+                .get(&k)
+                .map_or(false, |versioned| versioned.is_deleted()),
+            "ERR_RECORD_DELETED"
+        );
+
+        contract.debit(&account_id, amount.0);
+
+        let versioned = contract
+            .hashes
+            .entry(k)
+            .or_insert(VersionedBalances::V2(BalancesV2 {
+                deposited: 0,
+                total: 0,
+                earned: 0,
+                updated_at: 0,
+                deleted: false,
+            }));
+        let mut bal = versioned.clone().get_balance();
+        bal.deposited += amount.0;
+        bal.total += amount.0;
+        bal.updated_at = env::block_timestamp();
+        *versioned = VersionedBalances::V2(bal);
+    }
+
+    /// Moves `amount` of the deposited balance of the caller's record under `k` back into their
+    /// `funders` balance, undoing `allocate_to_record`. The same checks apply, the record has to
+    /// exist and at most its `deposited` balance can be moved.
+    pub fn release_from_record(&mut self, k: String, amount: U128) {
+        let account_id = env::predecessor_account_id();
+        require!(is_own_key(&account_id, &k), "ERR_NOT_KEY_OWNER");
+        let contract = self.contract_mut();
+        require!(!contract.frozen.contains(&account_id), "ERR_FROZEN");
+        require!(!contract.escrows.contains_key(&k), "ERR_ESCROW_KEY");
+        let versioned = contract.hashes.get_mut(&k).expect("ERR_INVALID_KEY");
+        require!(!versioned.is_deleted(), "ERR_RECORD_DELETED");
+
+        let mut bal = versioned.clone().get_balance();
+        require!(bal.deposited >= amount.0, "ERR_INSUFFICIENT_BALANCE");
+        bal.deposited -= amount.0;
+        bal.total -= amount.0;
+        bal.updated_at = env::block_timestamp();
+        *versioned = VersionedBalances::V2(bal);
+
+        contract.admit_funder(&account_id);
+        contract.credit(&account_id, amount.0);
+    }
+
+    /// Moves the balances below `threshold` of a window of funders to the fee account, removing
+    /// those funders. Frozen funders are skipped. Returns the total amount swept. As with
+    /// `prune_zero_balances`, removed entries make the funders after them move into the window,
+    /// so repeat the same window until nothing is swept before moving on to the next one.
+    pub fn sweep_dust(&mut self, threshold: U128, from_index: u64, limit: u64) -> U128 {
+        let contract = self.contract_mut();
+        contract.assert_owner();
+        let treasury = contract.fee_account.clone().expect("ERR_NO_FEE_ACCOUNT");
+
+        let dust: Vec<AccountId> = contract
+            .funders
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .filter(|&(account_id, balance)| {
+                *balance < threshold.0
+                    && account_id != &treasury
+                    && !contract.frozen.contains(account_id)
+            })
+            .map(|(account_id, _)| account_id.clone())
+            .collect();
+        let mut swept = 0;
+        for account_id in &dust {
+            swept += contract.remove_funder(account_id);
+        }
+        contract.credit(&treasury, swept);
+
+        contract.emit_event(
+            "sweep_dust",
+            json!({
+                "accounts": dust.len(),
+                "amount": U128(swept),
+                "treasury": treasury,
+            }),
+        );
+        U128(swept)
+    }
+
+    /// Removes the funders of a window whose balance is 0, e.g. after withdrawing everything.
+    /// Returns how many were removed. Removed entries make the funders after them move into the
+    /// window, so operators cleaning up repeat the same window until it returns 0, and only then
+    /// move on to the next one.
+    pub fn prune_zero_balances(&mut self, from_index: u64, limit: u64) -> u64 {
+        let contract = self.contract_mut();
+        contract.assert_owner();
+
+        let empty: Vec<AccountId> = contract
+            .funders
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .filter(|(_, balance)| **balance == 0)
+            .map(|(account_id, _)| account_id.clone())
+            .collect();
+        for account_id in &empty {
+            contract.funders.remove(account_id);
+            contract.funder_info.remove(account_id);
+        }
+
+        contract.emit_event("prune_zero_balances", json!({ "accounts": empty.len() }));
+        empty.len() as u64
+    }
+
+    /// Sets the balance of `account_id` to zero by removing it from the funders, without
+    /// transferring anything, for dispute resolution. Frozen funders are under review and can't be
+    /// zeroed until they are unfrozen. Returns the amount zeroed and always emits a `zero_funder`
+    /// event with `reason`, also when there was nothing to zero.
+    pub fn zero_funder(&mut self, account_id: AccountId, reason: String) -> U128 {
+        require!(reason.len() <= MAX_REASON_LENGTH, "ERR_REASON_TOO_LONG");
+        let contract = self.contract_mut();
+        contract.assert_owner();
+        require!(!contract.frozen.contains(&account_id), "ERR_FROZEN");
+
+        let amount = contract.remove_funder(&account_id);
+        contract.emit_event(
+            "zero_funder",
+            json!({
+                "account_id": account_id,
+                "amount": U128(amount),
+                "reason": reason,
+            }),
+        );
+        U128(amount)
+    }
+
+    /// Overwrites the record under `k`. The balances are validated before anything is written,
+    /// `updated_at` is set to the current block timestamp. `reason` is recorded in the event.
+    pub fn set_balance(&mut self, k: String, mut balance: BalancesV2, reason: String) {
+        require!(balance.is_valid(), "ERR_INVALID_BALANCE");
+        require!(reason.len() <= MAX_REASON_LENGTH, "ERR_REASON_TOO_LONG");
+        let contract = self.contract_mut();
+        contract.assert_owner();
+
+        balance.updated_at = env::block_timestamp();
+        contract
+            .hashes
+            .insert(k.clone(), VersionedBalances::V2(balance));
+        contract.emit_event("set_balance", json!({ "key": k, "reason": reason }));
+    }
+
+    /// Syncs `deposited`/`total` of the `hashes` records keyed by a funder account with that
+    /// funder's balance, for a window of funders. Returns how many records were corrected.
+    pub fn reconcile(&mut self, from_index: u64, limit: u64) -> u64 {
+        let contract = self.contract_mut();
+        contract.assert_owner();
+
+        let Contract {
+            funders, hashes, ..
+        } = contract;
+        let mut reconciled = 0;
+        for (account_id, balance) in funders
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+        {
+            if let Some(versioned) = hashes.get_mut(account_id.as_str()) {
+                let mut bal = versioned.clone().get_balance();
+                if bal.deposited != *balance || bal.total != *balance {
+                    bal.deposited = *balance;
+                    bal.total = *balance;
+                    bal.updated_at = env::block_timestamp();
+                    *versioned = VersionedBalances::V2(bal);
+                    reconciled += 1;
+                }
+            }
+        }
+        reconciled
+    }
+
+    /// Marks the record under `k` deleted, keeping its data. It is hidden from `get_balance`
+    /// but can still be read with `get_balance_with_deleted`.
+    pub fn soft_delete_hash(&mut self, k: String) {
+        let contract = self.contract_mut();
+        contract.assert_owner();
+
+        let versioned = contract.hashes.get_mut(&k).expect("ERR_INVALID_KEY");
+        let mut bal = versioned.clone().get_balance();
+        bal.deleted = true;
+        bal.updated_at = env::block_timestamp();
+        *versioned = VersionedBalances::V2(bal);
+        contract.emit_event("soft_delete_hash", json!({ "key": k }));
+    }
+
+    /// Returns the upgraded balances under `k`, `None` for soft-deleted records. See
+    /// `get_balance_with_deleted` for missing keys.
+    pub fn get_balance(&self, k: String) -> Option<BalancesV2> {
+        self.get_balance_with_deleted(k, false)
+    }
+
+    /// Returns the upgraded balances under `k`, soft-deleted records only if `include_deleted`.
+    /// A missing key aborts the call unless lenient reads are enabled, in which case `None` is
+    /// returned.
+    pub fn get_balance_with_deleted(&self, k: String, include_deleted: bool) -> Option<BalancesV2> {
+        let versioned_option = match self.hashes().get(&k) {
+            Some(versioned) => versioned,
+            None if self.get_lenient_reads() => {
+                log!("ERR_INVALID_KEY: {}", k);
+                return None;
+            }
+            None => env::panic_str("ERR_INVALID_KEY"),
+        };
+        // converts older versions to the latest one
+        let bal = versioned_option.clone().get_balance();
+        (include_deleted || !bal.deleted).then(|| bal)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    use super::*;
+    use near_sdk::serde_json::{self, Value};
+    use near_sdk::test_utils::test_env::{alice, bob, carol};
+    use near_sdk::test_utils::{get_created_receipts, get_logs, VMContextBuilder};
+    use near_sdk::{testing_env, RuntimeFeesConfig, VMConfig};
+
+    fn set_predecessor_and_deposit(predecessor: AccountId, deposit: Balance) {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(predecessor)
+            .attached_deposit(deposit)
+            .build())
+    }
+
+    /// writes the state to storage like the end of a contract call does
+    fn persist(contract: &mut VersionedContract) {
+        env::state_write(&*contract);
+        let contract = contract.contract_mut();
+        contract.funders.flush();
+        contract.funder_info.flush();
+    }
+
+    fn users(n: usize) -> Vec<AccountId> {
+        (0..n)
+            .map(|i| format!("user{}.near", i).parse().unwrap())
+            .collect()
+    }
+
+    /// moves the block timestamp of `builder` `secs` seconds forward and applies it
+    fn advance_time(builder: &mut VMContextBuilder, secs: u64) {
+        let now = builder.context.block_timestamp;
+        testing_env!(builder.block_timestamp(now + secs * 1_000_000_000).build());
+    }
+
+    /// NEP-297 events logged during the current test context
+    fn events() -> Vec<Value> {
+        get_logs()
+            .iter()
+            .filter_map(|log| log.strip_prefix("EVENT_JSON:"))
+            .map(|event| serde_json::from_str(event).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn basic() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 8);
+        contract.deposit();
+
+        set_predecessor_and_deposit(alice(), 10);
+        contract.deposit();
+
+        set_predecessor_and_deposit(bob(), 20);
+        contract.deposit();
+
+        assert_eq!(contract.get_deposit(&alice()), Some(&10));
+        assert_eq!(contract.get_deposit(&bob()), Some(&28));
+        assert_eq!(contract.get_nonce(), 3);
+    }
+
+    #[test]
+    fn with_upgrade() {
+        let mut contract = {
+            let mut funders = UnorderedMap::new(b"f");
+            funders.insert(bob(), 8);
+            let hashes: HashMap<String, VersionedBalances> = HashMap::new();
+
+            VersionedContract::V0(ContractV0 { funders, hashes })
+        };
+        assert_eq!(contract.get_nonce(), 0);
+        assert!(matches!(contract, VersionedContract::V0(_)));
+
+        // upgrade on-fly
+        set_predecessor_and_deposit(alice(), 0);
+        let k = "some_key".to_string();
+        contract.add_hash(k.clone());
+        // the V0 contract now holds a V1 record, reads upgrade either version
+        contract.force_balance_version(k.clone(), 1);
+        assert_eq!(
+            contract.get_balance(k),
+            Some(BalancesV2 {
+                deposited: 1,
+                total: 1,
+                earned: 0,
+                updated_at: 0,
+                deleted: false,
+            })
+        );
+        // end
+
+        set_predecessor_and_deposit(alice(), 1000);
+        contract.deposit();
+
+        assert!(matches!(contract, VersionedContract::V2(_)));
+        assert_eq!(contract.get_nonce(), 1);
+        assert_eq!(contract.get_deposit(&alice()), Some(&1000));
+        assert_eq!(contract.get_deposit(&bob()), Some(&8));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_REGISTERED")]
+    fn deposit_requires_registration() {
+        let mut contract = VersionedContract::default();
+        // the default test context deploys the contract on alice, who is therefore the owner
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_require_registration(true);
+
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+    }
+
+    #[test]
+    fn deposit_after_registration() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_require_registration(true);
+
+        contract.add_hash(bob().to_string());
+
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+
+        assert_eq!(contract.get_deposit(&bob()), Some(&10));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_KEY_OWNER")]
+    fn accounts_cannot_register_themselves() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 0);
+        contract.add_hash(bob().to_string());
+    }
+
+    #[test]
+    fn reconcile_fixes_drifted_record() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+
+        set_predecessor_and_deposit(alice(), 0);
+        // record starts with placeholder balances that don't match the deposit
+        contract.add_hash(bob().to_string());
+        assert_eq!(contract.reconcile(0, 10), 1);
+        assert_eq!(
+            contract.get_balance(bob().to_string()),
+            Some(BalancesV2 {
+                deposited: 10,
+                total: 10,
+                earned: 0,
+                updated_at: 0,
+                deleted: false,
+            })
+        );
+        // already in sync
+        assert_eq!(contract.reconcile(0, 10), 0);
+    }
+
+    #[test]
+    fn default_is_latest_version() {
+        // update together with `LATEST_STATE_VERSION` when a new variant is added
+        let contract = VersionedContract::default();
+        assert!(matches!(contract, VersionedContract::V2(_)));
+        assert_eq!(contract.state_version(), LATEST_STATE_VERSION);
+    }
+
+    #[test]
+    fn event_index_is_sequential_across_events() {
+        let mut contract = VersionedContract::V1(ContractV1::default());
+
+        // the first deposit upgrades the contract, emitting an upgrade event before the deposit
+        set_predecessor_and_deposit(alice(), 1000);
+        contract.deposit();
+
+        let events = events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["event"], "upgrade");
+        assert_eq!(events[0]["data"][0]["event_index"], 0);
+        assert_eq!(events[0]["data"][0]["from_version"], 1);
+        assert_eq!(events[1]["event"], "deposit");
+        assert_eq!(events[1]["data"][0]["event_index"], 1);
+        assert_eq!(contract.event_index(), 2);
+    }
+
+    #[test]
+    fn allocate_part_of_balance_to_record() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+
+        let k = derive_key(&bob(), "savings");
+        contract.allocate_to_record(k.clone(), U128(4));
+        contract.allocate_to_record(k.clone(), U128(2));
+
+        assert_eq!(contract.get_deposit(&bob()), Some(&4));
+        assert_eq!(
+            contract.get_balance(k),
+            Some(BalancesV2 {
+                deposited: 6,
+                total: 6,
+                earned: 0,
+                updated_at: 0,
+                deleted: false,
+            })
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INSUFFICIENT_BALANCE")]
+    fn allocate_more_than_balance() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+
+        contract.allocate_to_record(derive_key(&bob(), "savings"), U128(11));
+    }
+
+    #[test]
+    fn release_part_of_record_balance() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+        let k = derive_key(&bob(), "savings");
+        contract.allocate_to_record(k.clone(), U128(6));
+
+        contract.release_from_record(k.clone(), U128(4));
+        assert_eq!(contract.get_deposit(&bob()), Some(&8));
+        assert_eq!(contract.get_balance(k.clone()).unwrap().deposited, 2);
+        assert_eq!(contract.total_deposited(), U128(8));
+
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            contract.release_from_record(k.clone(), U128(3))
+        }));
+        assert!(result.is_err());
+        // only the owner of the key can release from it
+        set_predecessor_and_deposit(carol(), 0);
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            contract.release_from_record(k.clone(), U128(1))
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_FROZEN")]
+    fn frozen_funder_cannot_release() {
+        contract_with_frozen_bob().release_from_record(derive_key(&bob(), "savings"), U128(1));
+    }
+
+    #[test]
+    fn allocate_only_to_own_keys() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+        let escrow_key = derive_key(&bob(), "escrow");
+        set_predecessor_and_deposit(bob(), 5);
+        contract.deposit_escrow(escrow_key.clone(), carol());
+
+        set_predecessor_and_deposit(bob(), 0);
+        for k in [
+            "savings".to_string(),
+            bob().to_string(),
+            derive_key(&carol(), "savings"),
+            format!("{}x:savings", bob()),
+            escrow_key,
+        ] {
+            let result = catch_unwind(AssertUnwindSafe(|| {
+                contract.allocate_to_record(k.clone(), U128(4))
+            }));
+            assert!(result.is_err(), "allocated to {}", k);
+        }
+        assert_eq!(contract.get_deposit(&bob()), Some(&10));
+    }
+
+    #[test]
+    fn audit_page_matches_individual_views() {
+        let mut contract = VersionedContract::V0(ContractV0::default());
+        set_predecessor_and_deposit(alice(), 0);
+        contract.add_hash("a".to_string());
+        contract.add_hash("b".to_string());
+
+        set_predecessor_and_deposit(bob(), 8);
+        contract.deposit();
+        set_predecessor_and_deposit(alice(), 10);
+        contract.deposit();
+        contract.add_hash("c".to_string());
+
+        let page = contract.audit_page(1, 2);
+        assert_eq!(page.funders, contract.get_funders(1, 2));
+        assert_eq!(page.funders, vec![(alice(), U128(10))]);
+        assert_eq!(page.hash_versions, contract.get_hash_versions(1, 2));
+        assert_eq!(
+            page.hash_versions,
+            vec![("b".to_string(), 0), ("c".to_string(), 2)]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INVALID_KEY")]
+    fn strict_read_of_missing_key() {
+        let contract = VersionedContract::default();
+        contract.get_balance("missing".to_string());
+    }
+
+    #[test]
+    fn lenient_read_of_missing_key() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_lenient_reads(true);
+
+        assert_eq!(contract.get_balance("missing".to_string()), None);
+    }
+
+    #[test]
+    fn funder_versions_after_partial_migration() {
+        let mut contract = {
+            let mut funders = UnorderedMap::new(b"f");
+            funders.insert(bob(), 8);
+            funders.insert(alice(), 10);
+
+            VersionedContract::V1(ContractV1 {
+                funders,
+                nonce: 0,
+                hashes: HashMap::new(),
+            })
+        };
+        assert_eq!(
+            contract.funder_version_histogram(),
+            HashMap::from([(LEGACY_FUNDER_VERSION, 2)])
+        );
+
+        // depositing moves alice to the new funder schema
+        set_predecessor_and_deposit(alice(), 5);
+        contract.deposit();
+
+        assert_eq!(
+            contract.funder_version_histogram(),
+            HashMap::from([(LEGACY_FUNDER_VERSION, 1), (FUNDER_INFO_VERSION, 1)])
+        );
+    }
+
+    #[test]
+    fn force_balance_version_downgrades_record() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        let k = "some_key".to_string();
+        contract.add_hash(k.clone());
+        assert_eq!(contract.get_hash_versions(0, 1), vec![(k.clone(), 2)]);
+
+        contract.force_balance_version(k.clone(), 0);
+        assert_eq!(contract.get_hash_versions(0, 1), vec![(k.clone(), 0)]);
+        // reads still see the upgraded record
+        assert_eq!(
+            contract.get_balance(k),
+            Some(BalancesV2 {
+                deposited: 1,
+                total: 1,
+                earned: 0,
+                updated_at: 0,
+                deleted: false,
+            })
+        );
+    }
+
+    #[test]
+    fn deposit_with_fee_fills_cap_exactly() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_max_total(Some(U128(100)));
+        contract.set_fee(1_000, Some(carol()));
+
+        set_predecessor_and_deposit(bob(), 90);
+        contract.deposit();
+        assert_eq!(contract.get_deposit(&bob()), Some(&81));
+        assert_eq!(contract.get_deposit(&carol()), Some(&9));
+
+        // only 10 fit under the cap, the fee is taken from those and 40 are refunded
+        set_predecessor_and_deposit(bob(), 50);
+        contract.deposit();
+        assert_eq!(contract.get_deposit(&bob()), Some(&90));
+        assert_eq!(contract.get_deposit(&carol()), Some(&10));
+        assert_eq!(contract.total_deposited(), U128(100));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_MAX_FUNDERS_REACHED")]
+    fn fee_account_counts_against_max_funders() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_max_funders(Some(1));
+        contract.set_fee(1_000, Some(carol()));
+
+        // bob takes the only slot, carol can't be added to collect the fee
+        set_predecessor_and_deposit(bob(), 90);
+        contract.deposit();
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_MAX_TOTAL_REACHED")]
+    fn deposit_above_reached_cap() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_max_total(Some(U128(100)));
+
+        set_predecessor_and_deposit(bob(), 100);
+        contract.deposit();
+        set_predecessor_and_deposit(bob(), 1);
+        contract.deposit();
+    }
+
+    #[test]
+    fn known_balance_versions_match_variants() {
+        let contract = VersionedContract::default();
+        let v0 = VersionedBalances::V0(Balances {
+            deposited: 1,
+            total: 1,
+        });
+        let v1 = VersionedBalances::V1(BalancesV1 {
+            deposited: 1,
+            total: 1,
+            earned: 0,
+        });
+        let v2 = v0.upgrade();
+
+        assert_eq!(
+            contract.known_balance_versions(),
+            vec![v0.version(), v1.version(), v2.version()]
+        );
+        assert_eq!(contract.known_versions(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn set_balance_rejects_invalid_record() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        let k = "some_key".to_string();
+        contract.add_hash(k.clone());
+
+        set_predecessor_and_deposit(alice(), 0);
+        let valid = BalancesV2 {
+            deposited: 5,
+            total: 8,
+            earned: 3,
+            updated_at: 0,
+            deleted: false,
+        };
+        contract.set_balance(k.clone(), valid.clone(), "correction".to_string());
+
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            contract.set_balance(
+                k.clone(),
+                BalancesV2 {
+                    deposited: 5,
+                    total: 4,
+                    earned: 0,
+                    updated_at: 0,
+                    deleted: false,
+                },
+                "correction".to_string(),
+            )
+        }));
+        assert!(result.is_err());
+        assert_eq!(contract.get_balance(k), Some(valid));
+    }
+
+    #[test]
+    fn only_owner_can_administer() {
+        let contract = VersionedContract::default();
+        assert_eq!(contract.get_owner(), alice());
+        assert!(contract.can_administer(alice()));
+        assert!(!contract.can_administer(bob()));
+    }
+
+    #[test]
+    fn deposit_split_at_batch_limit() {
+        let mut contract = VersionedContract::default();
+        let beneficiaries = users(MAX_BATCH_SIZE);
+
+        set_predecessor_and_deposit(bob(), 2 * MAX_BATCH_SIZE as Balance + 1);
+        contract.deposit_split(beneficiaries.clone());
+
+        assert_eq!(contract.get_deposit(&beneficiaries[0]), Some(&3));
+        assert_eq!(contract.get_deposit(&beneficiaries[1]), Some(&2));
+        assert_eq!(contract.get_deposit(&bob()), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_BATCH_TOO_LARGE")]
+    fn deposit_split_above_batch_limit() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 100);
+        contract.deposit_split(users(MAX_BATCH_SIZE + 1));
+    }
+
+    #[test]
+    fn banned_accounts_cannot_use_deposit_split() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.batch_admin(vec![AdminOp::Ban(bob())]);
+
+        set_predecessor_and_deposit(bob(), 10);
+        let result = catch_unwind(AssertUnwindSafe(|| contract.deposit_split(vec![bob()])));
+        assert!(result.is_err());
+
+        // banned beneficiaries can't be credited by others either
+        set_predecessor_and_deposit(carol(), 10);
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            contract.deposit_split(vec![carol(), bob()])
+        }));
+        assert!(result.is_err());
+        assert_eq!(contract.get_deposit(&bob()), None);
+        assert_eq!(contract.get_deposit(&carol()), None);
+    }
+
+    #[test]
+    fn group_total_ignores_unknown_accounts() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 8);
+        contract.deposit();
+        set_predecessor_and_deposit(alice(), 10);
+        contract.deposit();
+
+        assert_eq!(
+            contract.group_total(vec![alice(), bob(), carol()]),
+            U128(18)
+        );
+    }
+
+    #[test]
+    fn reemit_window_of_recent_deposits() {
+        let mut contract = VersionedContract::default();
+        let mut live = Vec::new();
+        for deposit in [3, 5, 7] {
+            set_predecessor_and_deposit(bob(), deposit);
+            contract.deposit();
+            live.extend(events());
+        }
+
+        set_predecessor_and_deposit(alice(), 0);
+        contract.reemit_recent(1, 5);
+
+        let events = events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["data"][0]["amount"], "5");
+        assert_eq!(events[0]["data"][0]["event_index"], 1);
+        assert_eq!(events[1]["data"][0]["amount"], "7");
+        assert_eq!(events[1]["data"][0]["event_index"], 2);
+        // the same data as the live events, so the running totals can be replayed
+        assert_eq!(events[..], live[1..]);
+    }
+
+    #[test]
+    fn fee_rounding_modes() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_fee(1_000, Some(carol()));
+
+        // 10% of 15 is 1.5, truncated to 1
+        set_predecessor_and_deposit(bob(), 15);
+        contract.deposit();
+        assert_eq!(contract.get_deposit(&bob()), Some(&14));
+        assert_eq!(contract.get_deposit(&carol()), Some(&1));
+
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_fee_round_up(true);
+
+        // and rounded up to 2
+        set_predecessor_and_deposit(bob(), 15);
+        contract.deposit();
+        assert_eq!(contract.get_deposit(&bob()), Some(&27));
+        assert_eq!(contract.get_deposit(&carol()), Some(&3));
+    }
+
+    #[test]
+    fn withdrawals_add_up_to_total_withdrawn() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+
+        set_predecessor_and_deposit(bob(), 0);
+        contract.withdraw(U128(3));
+        contract.withdraw(U128(4));
+
+        assert_eq!(contract.get_deposit(&bob()), Some(&3));
+        assert_eq!(contract.total_withdrawn(), U128(7));
+        assert_eq!(contract.total_deposited(), U128(3));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_INSUFFICIENT_BALANCE")]
+    fn withdraw_more_than_balance() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+
+        contract.withdraw(U128(11));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_ZERO_AMOUNT")]
+    fn withdraw_zero() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+
+        contract.withdraw(U128(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_KEY_EXISTS")]
+    fn add_hash_does_not_overwrite() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.add_hash("some_key".to_string());
+        contract.add_hash("some_key".to_string());
+    }
+
+    #[test]
+    fn state_bytes_grow_with_new_funder() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 10);
+        persist(&mut contract);
+        let before = contract.approx_state_bytes();
+
+        contract.deposit();
+        persist(&mut contract);
+        assert!(contract.approx_state_bytes() > before);
+    }
+
+    #[test]
+    fn migrate_from_v1() {
+        let contract = {
+            let mut funders = UnorderedMap::new(b"f");
+            funders.insert(bob(), 8);
+            let mut hashes = HashMap::new();
+            hashes.insert(
+                "some_key".to_string(),
+                VersionedBalances::V0(Balances {
+                    deposited: 1,
+                    total: 1,
+                }),
+            );
+
+            VersionedContract::V1(ContractV1 {
+                funders,
+                nonce: 5,
+                hashes,
+            })
+        };
+        env::state_write(&contract);
+        // dropping flushes the funders to storage
+        drop(contract);
+
+        let contract = VersionedContract::migrate();
+        assert!(matches!(contract, VersionedContract::V2(_)));
+        // v1 fields survive
+        assert_eq!(contract.get_nonce(), 5);
+        assert_eq!(contract.get_deposit(&bob()), Some(&8));
+        assert_eq!(
+            contract.get_hash_versions(0, 10),
+            vec![("some_key".to_string(), 0)]
+        );
+        // v2 fields start from their defaults
+        assert_eq!(contract.get_owner(), alice());
+        assert!(!contract.get_require_registration());
+        assert_eq!(contract.get_max_total(), None);
+        assert_eq!(contract.total_deposited(), U128(8));
+        assert_eq!(contract.total_withdrawn(), U128(0));
+        assert_eq!(contract.event_index(), 1);
+    }
+
+    #[test]
+    fn fee_account_reflects_configuration() {
+        let mut contract = VersionedContract::default();
+        assert_eq!(contract.fee_account(), None);
+
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_fee(100, Some(carol()));
+        assert_eq!(contract.fee_account(), Some(carol()));
+    }
+
+    #[test]
+    fn deposit_event_carries_running_total() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 8);
+        contract.deposit();
+
+        set_predecessor_and_deposit(bob(), 20);
+        contract.deposit();
+
+        let events = events();
+        assert_eq!(events[0]["event"], "deposit");
+        assert_eq!(events[0]["data"][0]["amount"], "20");
+        assert_eq!(events[0]["data"][0]["new_total"], "28");
+    }
+
+    #[test]
+    fn migration_pending_until_records_upgraded() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.add_hash("a".to_string());
+        contract.add_hash("b".to_string());
+        assert!(!contract.migration_pending(10));
+
+        contract.force_balance_version("b".to_string(), 0);
+        assert!(contract.migration_pending(10));
+    }
+
+    #[test]
+    fn average_deposit_of_funders() {
+        let mut contract = VersionedContract::default();
+        assert_eq!(contract.average_deposit(), U128(0));
+
+        for (funder, deposit) in [(alice(), 10), (bob(), 20), (carol(), 31)] {
+            set_predecessor_and_deposit(funder, deposit);
+            contract.deposit();
+        }
+        assert_eq!(contract.num_funders(), 3);
+        assert_eq!(contract.average_deposit(), U128(20));
+    }
+
+    #[test]
+    fn are_funders_in_order() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 8);
+        contract.deposit();
+
+        assert_eq!(
+            contract.are_funders(vec![alice(), bob(), carol(), bob()]),
+            vec![false, true, false, true]
+        );
+    }
+
+    #[test]
+    fn deposits_continue_while_withdrawals_paused() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.pause_withdrawals();
+        assert!(contract.get_withdrawals_paused());
+
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+        assert_eq!(contract.get_deposit(&bob()), Some(&10));
+
+        set_predecessor_and_deposit(bob(), 0);
+        let result = catch_unwind(AssertUnwindSafe(|| contract.withdraw(U128(5))));
+        assert!(result.is_err());
+        assert_eq!(contract.get_deposit(&bob()), Some(&10));
+
+        set_predecessor_and_deposit(alice(), 0);
+        contract.unpause_withdrawals();
+        set_predecessor_and_deposit(bob(), 0);
+        contract.withdraw(U128(5));
+        assert_eq!(contract.get_deposit(&bob()), Some(&5));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_WITHDRAWALS_PAUSED")]
+    fn withdraw_while_paused() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.pause_withdrawals();
+
+        set_predecessor_and_deposit(bob(), 0);
+        contract.withdraw(U128(5));
+    }
+
+    #[cfg(feature = "debug")]
+    #[test]
+    fn variant_tag_of_default() {
+        let contract = VersionedContract::default();
+        assert_eq!(contract.variant_tag(), LATEST_STATE_VERSION);
+        assert_eq!(
+            VersionedContract::V0(ContractV0::default()).variant_tag(),
+            0
+        );
+    }
+
+    #[test]
+    fn escrow_deposit_and_release() {
+        let mut contract = VersionedContract::default();
+        let k = derive_key(&bob(), "escrow");
+        set_predecessor_and_deposit(bob(), 50);
+        contract.deposit_escrow(k.clone(), carol());
+
+        assert_eq!(
+            contract.get_escrow(k.clone()),
+            Some(Escrow {
+                depositor: bob(),
+                release_to: carol(),
+                amount: 50,
+            })
+        );
+        assert_eq!(
+            contract.get_balance(k.clone()),
+            Some(BalancesV2 {
+                deposited: 50,
+                total: 50,
+                earned: 0,
+                updated_at: 0,
+                deleted: false,
+            })
+        );
+
+        set_predecessor_and_deposit(bob(), 0);
+        contract.release_escrow(k.clone());
+
+        assert!(get_created_receipts()
+            .iter()
+            .any(|receipt| receipt.receiver_id == carol()));
+        // a second release can't start while the transfer is in flight
+        let result = catch_unwind(AssertUnwindSafe(|| contract.release_escrow(k.clone())));
+        assert!(result.is_err());
+
+        testing_env!(
+            VMContextBuilder::new()
+                .predecessor_account_id(alice())
+                .build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])]
+        );
+        assert!(contract.on_escrow_released(k.clone()));
+        assert_eq!(contract.get_escrow(k.clone()), None);
+        assert!(contract.get_hash_versions(0, 10).is_empty());
+    }
+
+    #[test]
+    fn failed_escrow_release_keeps_escrow() {
+        let mut contract = VersionedContract::default();
+        let k = derive_key(&bob(), "escrow");
+        set_predecessor_and_deposit(bob(), 50);
+        contract.deposit_escrow(k.clone(), carol());
+        contract.release_escrow(k.clone());
+
+        testing_env!(
+            VMContextBuilder::new()
+                .predecessor_account_id(alice())
+                .build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        assert!(!contract.on_escrow_released(k.clone()));
+        assert_eq!(
+            contract.get_escrow(k.clone()).map(|escrow| escrow.amount),
+            Some(50)
+        );
+        assert_eq!(contract.get_hash_versions(0, 10), vec![(k.clone(), 2)]);
+
+        // the release can be retried
+        set_predecessor_and_deposit(bob(), 0);
+        contract.release_escrow(k);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_ALLOWED")]
+    fn escrow_release_by_stranger() {
+        let mut contract = VersionedContract::default();
+        let k = derive_key(&bob(), "escrow");
+        set_predecessor_and_deposit(bob(), 50);
+        contract.deposit_escrow(k.clone(), carol());
+
+        set_predecessor_and_deposit(carol(), 0);
+        contract.release_escrow(k);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_KEY_OWNER")]
+    fn escrow_cannot_take_registration_key() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 50);
+        contract.deposit_escrow(alice().to_string(), carol());
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_BANNED")]
+    fn banned_account_cannot_deposit_escrow() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.batch_admin(vec![AdminOp::Ban(bob())]);
+
+        set_predecessor_and_deposit(bob(), 50);
+        contract.deposit_escrow(derive_key(&bob(), "escrow"), carol());
+    }
+
+    #[test]
+    fn deposits_tracked_per_block() {
+        let mut contract = VersionedContract::default();
+        let deposit_at = |predecessor: AccountId, deposit: Balance, block: u64| {
+            testing_env!(VMContextBuilder::new()
+                .predecessor_account_id(predecessor)
+                .attached_deposit(deposit)
+                .block_index(block)
+                .build())
+        };
+
+        deposit_at(bob(), 8, 10);
+        contract.deposit();
+        deposit_at(alice(), 10, 10);
+        contract.deposit();
+        assert_eq!(contract.deposits_this_block(), U128(18));
+
+        deposit_at(bob(), 3, 11);
+        assert_eq!(contract.deposits_this_block(), U128(0));
+        contract.deposit();
+        assert_eq!(contract.deposits_this_block(), U128(3));
+    }
+
+    #[test]
+    fn allowlist_gates_deposits_when_enabled() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.allow(bob());
+
+        // everyone can deposit while the allowlist is disabled
+        set_predecessor_and_deposit(carol(), 5);
+        contract.deposit();
+        assert_eq!(contract.get_deposit(&carol()), Some(&5));
+
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_allowlist_enabled(true);
+
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+        assert_eq!(contract.get_deposit(&bob()), Some(&10));
+
+        set_predecessor_and_deposit(carol(), 5);
+        let result = catch_unwind(AssertUnwindSafe(|| contract.deposit()));
+        assert!(result.is_err());
+        assert_eq!(contract.get_deposit(&carol()), Some(&5));
+    }
+
+    #[test]
+    fn allowlist_gates_deposit_split() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_allowlist_enabled(true);
+        contract.allow(bob());
+
+        set_predecessor_and_deposit(carol(), 10);
+        let result = catch_unwind(AssertUnwindSafe(|| contract.deposit_split(vec![carol()])));
+        assert!(result.is_err());
+        let result = catch_unwind(AssertUnwindSafe(|| contract.deposit_split(vec![bob()])));
+        assert!(result.is_err());
+
+        // allowlisted accounts can't fund accounts outside the allowlist either
+        set_predecessor_and_deposit(bob(), 10);
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            contract.deposit_split(vec![bob(), carol()])
+        }));
+        assert!(result.is_err());
+
+        contract.deposit_split(vec![bob()]);
+        assert_eq!(contract.get_deposit(&bob()), Some(&10));
+        assert_eq!(contract.get_deposit(&carol()), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_ALLOWLISTED")]
+    fn deposit_after_disallow() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_allowlist_enabled(true);
+        contract.allow(bob());
+        contract.disallow(bob());
+
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+    }
+
+    #[test]
+    fn balance_diff_is_signed() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 8);
+        contract.deposit();
+        set_predecessor_and_deposit(alice(), 10);
+        contract.deposit();
+
+        assert_eq!(contract.balance_diff(alice(), bob()), I128(2));
+        assert_eq!(contract.balance_diff(bob(), alice()), I128(-2));
+        assert_eq!(contract.balance_diff(bob(), carol()), I128(8));
+    }
+
+    #[test]
+    fn funder_info_tracks_deposit_times() {
+        let mut contract = VersionedContract::default();
+        let mut builder = VMContextBuilder::new();
+        builder.predecessor_account_id(bob()).attached_deposit(5);
+        advance_time(&mut builder, 10);
+        contract.deposit();
+
+        advance_time(&mut builder, 60);
+        contract.deposit();
+
+        assert_eq!(
+            contract.get_funder_info(bob()),
+            Some(FunderInfo {
+                first_deposit_at: Some(10_000_000_000),
+                last_deposit_at: Some(70_000_000_000),
+                last_deposit_nonce: Some(2),
+                tracked_since: 10_000_000_000,
+                balance_seconds: 300,
+                accrued_at: 70_000_000_000,
+            })
+        );
+    }
+
+    #[test]
+    fn upgrade_policy_reflects_configuration() {
+        let mut contract = VersionedContract::default();
+        assert_eq!(contract.upgrade_policy(), UpgradePolicy::default());
+
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_upgrade_policy(false, Some("dao.near".parse().unwrap()));
+        assert_eq!(
+            contract.upgrade_policy(),
+            UpgradePolicy {
+                code_upgrades_allowed: false,
+                upgrader_id: Some("dao.near".parse().unwrap()),
+                // derived from the code, not configurable
+                automatic_migration: true,
+            }
+        );
+    }
+
+    #[test]
+    fn deploy_code_follows_upgrade_policy() {
+        let mut contract = VersionedContract::default();
+        let dao: AccountId = "dao.near".parse().unwrap();
+        let code = Base64VecU8(vec![0, 97, 115, 109]);
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_upgrade_policy(false, None);
+        let result = catch_unwind(AssertUnwindSafe(|| contract.deploy_code(code.clone())));
+        assert!(result.is_err());
+
+        // once an upgrader is set, the owner can't deploy anymore
+        contract.set_upgrade_policy(true, Some(dao.clone()));
+        let result = catch_unwind(AssertUnwindSafe(|| contract.deploy_code(code.clone())));
+        assert!(result.is_err());
+
+        set_predecessor_and_deposit(dao, 0);
+        contract.deploy_code(code);
+        assert!(get_created_receipts()
+            .iter()
+            .any(|receipt| receipt.receiver_id == alice()));
+    }
+
+    #[test]
+    fn top_up_to_target_refunds_remainder() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+
+        set_predecessor_and_deposit(bob(), 40);
+        contract.top_up_to(U128(25));
+
+        assert_eq!(contract.get_deposit(&bob()), Some(&25));
+        assert_eq!(contract.total_deposited(), U128(25));
+        assert!(get_created_receipts()
+            .iter()
+            .any(|receipt| receipt.receiver_id == bob()));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_ALREADY_ABOVE_TARGET")]
+    fn top_up_when_at_target() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+
+        contract.top_up_to(U128(10));
+    }
+
+    #[test]
+    fn has_record_under_salt() {
+        let mut contract = VersionedContract::default();
+        let k = contract.derive_key(bob(), "savings".to_string());
+        contract.add_hash(k);
+
+        assert!(contract.has_record(bob(), "savings".to_string()));
+        assert!(!contract.has_record(bob(), "checking".to_string()));
+        assert!(!contract.has_record(alice(), "savings".to_string()));
+    }
+
+    #[test]
+    fn estimate_gas_for_pending_upgrades() {
+        let mut contract = VersionedContract::V0(ContractV0::default());
+        set_predecessor_and_deposit(alice(), 0);
+        for k in ["a", "b", "c", "d"] {
+            contract.add_hash(k.to_string());
+        }
+        assert!(contract.estimate_upgrade_gas(2).0 > 0);
+
+        let mut contract = VersionedContract::default();
+        contract.add_hash("a".to_string());
+        assert_eq!(contract.estimate_upgrade_gas(2), U128(0));
+    }
+
+    #[test]
+    fn distinct_amounts_sorted() {
+        let mut contract = VersionedContract::default();
+        for (funder, deposit) in [(alice(), 10), (bob(), 5), (carol(), 10)] {
+            set_predecessor_and_deposit(funder, deposit);
+            contract.deposit();
+        }
+
+        assert_eq!(contract.distinct_amounts(0, 10), vec![U128(5), U128(10)]);
+        assert_eq!(contract.distinct_amounts(1, 1), vec![U128(5)]);
+    }
+
+    #[test]
+    fn latest_state_is_not_migrated_again() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 8);
+        contract.deposit();
+        contract.deposit();
+        assert_eq!(contract.migration_count(), 0);
+
+        let mut contract = VersionedContract::V1(ContractV1::default());
+        contract.deposit();
+        contract.deposit();
+        assert_eq!(contract.migration_count(), 1);
+        assert_eq!(
+            events()
+                .iter()
+                .filter(|event| event["event"] == "upgrade")
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn set_and_get_profile() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 0);
+        contract.set_profile("hello".to_string());
+
+        assert_eq!(contract.get_profile(bob()), Some("hello".to_string()));
+        assert_eq!(contract.get_profile(alice()), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_PROFILE_TOO_LONG")]
+    fn set_profile_too_long() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 0);
+        contract.set_profile("a".repeat(MAX_PROFILE_LENGTH + 1));
+    }
+
+    #[test]
+    fn sweep_dust_into_treasury() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_fee(0, Some(carol()));
+        for (funder, deposit) in [(bob(), 3), (alice(), 100)] {
+            set_predecessor_and_deposit(funder, deposit);
+            contract.deposit();
+        }
+
+        set_predecessor_and_deposit(alice(), 0);
+        assert_eq!(contract.sweep_dust(U128(5), 0, 10), U128(3));
+
+        assert!(!contract.is_funder(bob()));
+        assert_eq!(contract.get_deposit(&carol()), Some(&3));
+        assert_eq!(contract.get_deposit(&alice()), Some(&100));
+        assert_eq!(contract.total_deposited(), U128(103));
+        assert_eq!(events()[0]["event"], "sweep_dust");
+    }
+
+    #[test]
+    fn sweep_dust_forgets_removed_funders() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_fee(0, Some(carol()));
+        contract.set_deposit_cooldown(Some(60));
+        set_predecessor_and_deposit(bob(), 3);
+        contract.deposit_with_chain(vec![]);
+        assert_eq!(contract.referral_depth(bob()), Some(0));
+
+        set_predecessor_and_deposit(alice(), 0);
+        contract.sweep_dust(U128(5), 0, 10);
+        assert_eq!(contract.deposit_cooldown_remaining(bob()), 0);
+        assert_eq!(contract.referral_depth(bob()), None);
+    }
+
+    #[test]
+    fn init_params_of_new_contract() {
+        testing_env!(VMContextBuilder::new().block_timestamp(42).build());
+        let contract = VersionedContract::new(bob(), Some(U128(1_000)));
+
+        assert_eq!(contract.get_owner(), bob());
+        assert_eq!(contract.get_max_total(), Some(U128(1_000)));
+        assert_eq!(
+            contract.init_params(),
+            Some(InitParams {
+                owner_id: bob(),
+                created_at: 42,
+                limits: Limits {
+                    max_total: Some(U128(1_000)),
+                    min_deposit: None,
+                    max_funders: None,
+                    max_per_account: None,
+                    deposit_cooldown: None,
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn default_method_deposits() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 8);
+        contract.__default();
+
+        assert_eq!(contract.get_deposit(&bob()), Some(&8));
+    }
+
+    #[test]
+    fn largest_of_recent_deposits() {
+        let mut contract = VersionedContract::default();
+        assert_eq!(contract.largest_recent_deposit(), None);
+
+        let mut builder = VMContextBuilder::new();
+        for (funder, deposit) in [(bob(), 8), (alice(), 30), (carol(), 12)] {
+            builder
+                .predecessor_account_id(funder)
+                .attached_deposit(deposit);
+            advance_time(&mut builder, 1);
+            contract.deposit();
+        }
+
+        assert_eq!(
+            contract.largest_recent_deposit(),
+            Some((alice(), U128(30), 2_000_000_000))
+        );
+    }
+
+    #[test]
+    fn reset_limits_restores_defaults() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_max_total(Some(U128(100)));
+        assert_ne!(contract.limits(), Limits::default());
+
+        contract.reset_limits_to_default();
+        assert_eq!(contract.limits(), Limits::default());
+        assert_eq!(events()[0]["event"], "reset_limits");
+    }
+
+    #[test]
+    fn withdrawals_locked_once_goal_reached() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_goal(U128(100));
+
+        // refunds are allowed while the goal is not reached
+        set_predecessor_and_deposit(bob(), 60);
+        contract.deposit();
+        set_predecessor_and_deposit(bob(), 0);
+        contract.withdraw(U128(10));
+        assert_eq!(contract.goal_status(), (U128(100), false));
+
+        set_predecessor_and_deposit(carol(), 50);
+        contract.deposit();
+        assert_eq!(contract.goal_status(), (U128(100), true));
+
+        set_predecessor_and_deposit(bob(), 0);
+        let result = catch_unwind(AssertUnwindSafe(|| contract.withdraw(U128(10))));
+        assert!(result.is_err());
+        assert_eq!(contract.get_deposit(&bob()), Some(&50));
+    }
+
+    #[test]
+    fn stale_keys_lists_only_old_records() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        for k in ["a", "b", "c", "d"] {
+            contract.add_hash(k.to_string());
+        }
+        contract.force_balance_version("b".to_string(), 0);
+        contract.force_balance_version("d".to_string(), 0);
+
+        assert_eq!(
+            contract.stale_keys(0, 10),
+            vec!["b".to_string(), "d".to_string()]
+        );
+        assert_eq!(contract.stale_keys(0, 2), vec!["b".to_string()]);
+    }
+
+    #[test]
+    fn events_carry_configured_prefix() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_event_prefix("staging-".to_string());
+
+        set_predecessor_and_deposit(bob(), 8);
+        contract.deposit();
+        assert_eq!(
+            events()[0]["standard"],
+            format!("staging-{}", events::EVENT_STANDARD)
+        );
+    }
+
+    #[test]
+    fn event_prefix_is_bounded() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        for prefix in [
+            "a".repeat(MAX_EVENT_PREFIX_LENGTH + 1),
+            "stag ing".to_string(),
+            "\"x\":".to_string(),
+        ] {
+            let result = catch_unwind(AssertUnwindSafe(|| contract.set_event_prefix(prefix)));
+            assert!(result.is_err());
+        }
+        contract.set_event_prefix("a".repeat(MAX_EVENT_PREFIX_LENGTH));
+        assert_eq!(
+            contract.get_event_prefix(),
+            "a".repeat(MAX_EVENT_PREFIX_LENGTH)
+        );
+    }
+
+    #[test]
+    fn verify_exported_snapshot() {
+        let mut contract = VersionedContract::default();
+        for (funder, deposit) in [(bob(), 8), (alice(), 10)] {
+            set_predecessor_and_deposit(funder, deposit);
+            contract.deposit();
+        }
+
+        let blob = contract.export_snapshot(0, MAX_SNAPSHOT_SIZE);
+        assert!(contract.verify_funders_blob(blob.clone()));
+
+        let mut snapshot = Vec::<(AccountId, Balance)>::try_from_slice(&blob.0).unwrap();
+        snapshot[0].1 += 1;
+        let tampered = Base64VecU8(snapshot.try_to_vec().unwrap());
+        assert!(!contract.verify_funders_blob(tampered));
+        assert!(!contract.verify_funders_blob(Base64VecU8(vec![1, 2, 3])));
+    }
+
+    #[test]
+    fn last_deposit_nonce_per_account() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 1);
+        contract.deposit();
+        set_predecessor_and_deposit(carol(), 1);
+        contract.deposit();
+        set_predecessor_and_deposit(bob(), 1);
+        contract.deposit();
+        set_predecessor_and_deposit(carol(), 1);
+        contract.deposit();
+        set_predecessor_and_deposit(bob(), 1);
+        contract.deposit();
+
+        assert_eq!(contract.get_nonce(), 5);
+        assert_eq!(contract.last_deposit_nonce(bob()), Some(5));
+        assert_eq!(contract.last_deposit_nonce(carol()), Some(4));
+        assert_eq!(contract.last_deposit_nonce(alice()), None);
+    }
+
+    #[test]
+    fn force_contract_upgrade_bumps_variant() {
+        let mut contract = VersionedContract::V0(ContractV0::default());
+
+        assert_eq!(contract.force_contract_upgrade(), LATEST_STATE_VERSION);
+        assert!(matches!(contract, VersionedContract::V2(_)));
+        assert_eq!(contract.force_contract_upgrade(), LATEST_STATE_VERSION);
+        assert_eq!(contract.migration_count(), 1);
+    }
+
+    #[test]
+    fn deposit_with_chain_pays_diminishing_bonuses() {
+        let mut contract = VersionedContract::default();
+        let accounts = users(4);
+        let (treasury, referrer, grand_referrer, funder) = (
+            accounts[0].clone(),
+            accounts[1].clone(),
+            accounts[2].clone(),
+            accounts[3].clone(),
+        );
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_fee(0, Some(treasury.clone()));
+        set_predecessor_and_deposit(treasury.clone(), 1_000);
+        contract.deposit();
+
+        set_predecessor_and_deposit(funder.clone(), 10_000);
+        contract.deposit_with_chain(vec![referrer.clone(), grand_referrer.clone(), bob()]);
+
+        assert_eq!(contract.get_deposit(&funder), Some(&10_000));
+        assert_eq!(contract.get_deposit(&referrer), Some(&100));
+        assert_eq!(contract.get_deposit(&grand_referrer), Some(&50));
+        assert_eq!(contract.get_deposit(&bob()), None);
+        assert_eq!(contract.get_deposit(&treasury), Some(&850));
+        assert_eq!(contract.referral_depth(funder), Some(2));
+        assert_eq!(contract.total_deposited(), U128(11_000));
+    }
+
+    /// contract whose treasury, `users(1)[0]`, holds 1_000 to pay referral bonuses from
+    fn contract_with_treasury() -> VersionedContract {
+        let mut contract = VersionedContract::default();
+        let treasury = users(1).remove(0);
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_fee(0, Some(treasury.clone()));
+        set_predecessor_and_deposit(treasury, 1_000);
+        contract.deposit();
+        contract
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_BANNED")]
+    fn deposit_with_chain_rejects_banned_referrer() {
+        let mut contract = contract_with_treasury();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.batch_admin(vec![AdminOp::Ban(carol())]);
+
+        set_predecessor_and_deposit(bob(), 10_000);
+        contract.deposit_with_chain(vec![carol()]);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_MAX_FUNDERS_REACHED")]
+    fn deposit_with_chain_respects_max_funders() {
+        let mut contract = contract_with_treasury();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_max_funders(Some(2));
+
+        // the treasury and bob take both slots, leaving none for the referrer
+        set_predecessor_and_deposit(bob(), 10_000);
+        contract.deposit_with_chain(vec![carol()]);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_DUPLICATE_REFERRER")]
+    fn deposit_with_chain_rejects_duplicate_referrers() {
+        let mut contract = contract_with_treasury();
+        set_predecessor_and_deposit(bob(), 10_000);
+        contract.deposit_with_chain(vec![carol(), carol()]);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_FROZEN")]
+    fn deposit_with_chain_rejects_frozen_treasury() {
+        let mut contract = contract_with_treasury();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.freeze_funder(users(1).remove(0));
+
+        set_predecessor_and_deposit(bob(), 10_000);
+        contract.deposit_with_chain(vec![carol()]);
+    }
+
+    #[test]
+    fn balance_after_withdraw_previews_remainder() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+
+        assert_eq!(
+            contract.balance_after_withdraw(bob(), U128(4)),
+            Some(U128(6))
+        );
+        assert_eq!(
+            contract.balance_after_withdraw(bob(), U128(10)),
+            Some(U128(0))
+        );
+        assert_eq!(contract.balance_after_withdraw(bob(), U128(11)), None);
+        assert_eq!(contract.balance_after_withdraw(carol(), U128(0)), None);
+    }
+
+    #[test]
+    fn total_cache_consistency_check() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+        set_predecessor_and_deposit(carol(), 5);
+        contract.deposit();
+        assert!(contract.total_cache_consistent());
+
+        contract.force_total_deposited(16);
+        assert!(!contract.total_cache_consistent());
+    }
+
+    #[test]
+    fn withdraw_attaches_callback() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_callback_gas(Gas(10_000_000_000_000));
+        assert_eq!(contract.get_callback_gas(), Gas(10_000_000_000_000));
+
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+        contract.withdraw(U128(4));
+
+        let receipts = get_created_receipts();
+        assert!(receipts.iter().any(|receipt| receipt.receiver_id == bob()));
+        assert!(receipts
+            .iter()
+            .any(|receipt| receipt.receiver_id == alice()));
+        assert_eq!(contract.get_deposit(&bob()), Some(&6));
+        assert_eq!(contract.total_withdrawn(), U128(4));
+    }
+
+    #[test]
+    fn callback_gas_is_bounded() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        for gas in [Gas(0), Gas(CALLBACK_GAS.0 - 1), Gas(MAX_CALLBACK_GAS.0 + 1)] {
+            let result = catch_unwind(AssertUnwindSafe(|| contract.set_callback_gas(gas)));
+            assert!(result.is_err());
+        }
+        assert_eq!(contract.get_callback_gas(), CALLBACK_GAS);
+
+        contract.set_callback_gas(MAX_CALLBACK_GAS);
+        assert_eq!(contract.get_callback_gas(), MAX_CALLBACK_GAS);
+    }
+
+    #[test]
+    fn is_empty_until_first_deposit() {
+        let mut contract = VersionedContract::default();
+        assert!(contract.is_empty());
+
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+        assert!(!contract.is_empty());
+        assert_eq!(contract.num_hashes(), 0);
+    }
+
+    #[test]
+    fn funders_since_filters_by_last_deposit() {
+        let mut contract = VersionedContract::default();
+        let mut builder = VMContextBuilder::new();
+        builder.predecessor_account_id(bob()).attached_deposit(5);
+        advance_time(&mut builder, 10);
+        contract.deposit();
+
+        builder.predecessor_account_id(carol());
+        advance_time(&mut builder, 10);
+        contract.deposit();
+
+        assert_eq!(contract.funders_since(0, 0, 10), vec![bob(), carol()]);
+        assert_eq!(contract.funders_since(10_000_000_000, 0, 10), vec![carol()]);
+        assert!(contract.funders_since(20_000_000_000, 0, 10).is_empty());
+    }
+
+    #[test]
+    fn reads_latest_balances_version() {
+        let mut contract = VersionedContract::default();
+        let mut builder = VMContextBuilder::new();
+        builder.predecessor_account_id(alice());
+        advance_time(&mut builder, 5);
+        let k = "some_key".to_string();
+        contract.add_hash(k.clone());
+        assert_eq!(
+            contract.get_hash_versions(0, 1),
+            vec![(k.clone(), LATEST_BALANCES_VERSION)]
+        );
+        assert_eq!(
+            contract.get_balance(k.clone()),
+            Some(BalancesV2 {
+                deposited: 1,
+                total: 1,
+                earned: 0,
+                updated_at: 5_000_000_000,
+                deleted: false,
+            })
+        );
+
+        // older versions are converted on read
+        contract.force_balance_version(k.clone(), 1);
+        assert_eq!(contract.get_balance(k).map(|bal| bal.updated_at), Some(0));
+    }
+
+    #[test]
+    fn twab_weights_holding_periods() {
+        let mut contract = VersionedContract::default();
+        let mut builder = VMContextBuilder::new();
+        builder.predecessor_account_id(bob()).attached_deposit(100);
+        advance_time(&mut builder, 0);
+        contract.deposit();
+
+        builder.attached_deposit(0);
+        advance_time(&mut builder, 10);
+        assert_eq!(contract.twab(bob()), U128(100));
+        contract.withdraw(U128(50));
+
+        advance_time(&mut builder, 10);
+        // 100 held for 10s, then 50 for 10s
+        assert_eq!(contract.twab(bob()), U128(75));
+        assert_eq!(contract.twab(carol()), U128(0));
+    }
+
+    #[test]
+    fn config_matches_getters() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_max_total(Some(U128(1_000)));
+        contract.set_fee(250, Some(carol()));
+        contract.set_require_registration(true);
+        contract.pause_withdrawals();
+
+        let config = contract.config();
+        assert_eq!(config.limits.max_total, Some(U128(1_000)));
+        assert_eq!(config.fee_bps, 250);
+        assert_eq!(config.fee_account, Some(carol()));
+        assert!(config.require_registration);
+        assert!(config.withdrawals_paused);
+        assert!(!config.allowlist_enabled);
+        assert_eq!(config.owner_id, contract.get_owner());
+        assert_eq!(config.upgrade_policy, contract.upgrade_policy());
+        assert_eq!(config.callback_gas, contract.get_callback_gas());
+    }
+
+    #[test]
+    fn batch_admin_applies_all_ops() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.batch_admin(vec![
+            AdminOp::Pause,
+            AdminOp::SetMaxTotal(Some(U128(100))),
+            AdminOp::SetMinDeposit(U128(5)),
+            AdminOp::Ban(bob()),
+        ]);
+
+        assert!(contract.get_withdrawals_paused());
+        assert_eq!(contract.limits().max_total, Some(U128(100)));
+        assert_eq!(contract.limits().min_deposit, Some(U128(5)));
+        assert!(contract.is_banned(bob()));
+
+        set_predecessor_and_deposit(carol(), 4);
+        let result = catch_unwind(AssertUnwindSafe(|| contract.deposit()));
+        assert!(result.is_err());
+        set_predecessor_and_deposit(bob(), 10);
+        let result = catch_unwind(AssertUnwindSafe(|| contract.deposit()));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn batch_admin_is_all_or_nothing() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            contract.batch_admin(vec![
+                AdminOp::Pause,
+                AdminOp::Ban(bob()),
+                AdminOp::Ban(alice()),
+                AdminOp::SetMinDeposit(U128(5)),
+            ])
+        }));
+
+        assert!(result.is_err());
+        assert!(!contract.get_withdrawals_paused());
+        assert!(!contract.is_banned(bob()));
+        assert_eq!(contract.limits(), Limits::default());
+    }
+
+    #[test]
+    fn funder_slots_decrease_with_funders() {
+        let mut contract = VersionedContract::default();
+        assert_eq!(contract.funder_slots_remaining(), u64::MAX);
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_max_funders(Some(2));
+        assert_eq!(contract.funder_slots_remaining(), 2);
+
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+        assert_eq!(contract.funder_slots_remaining(), 1);
+        set_predecessor_and_deposit(carol(), 10);
+        contract.deposit();
+        assert_eq!(contract.funder_slots_remaining(), 0);
+
+        // existing funders can still top up, new ones are rejected
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+        set_predecessor_and_deposit(alice(), 10);
+        let result = catch_unwind(AssertUnwindSafe(|| contract.deposit()));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn upgrade_history_records_upgrade() {
+        let mut contract = VersionedContract::V0(ContractV0::default());
+        assert!(contract.upgrade_history().is_empty());
+
+        let mut builder = VMContextBuilder::new();
+        advance_time(&mut builder, 42);
+        contract.force_contract_upgrade();
+        contract.force_contract_upgrade();
+
+        assert_eq!(
+            contract.upgrade_history(),
+            vec![(0, LATEST_STATE_VERSION, 42_000_000_000)]
+        );
+    }
+
+    #[test]
+    fn percentile_of_funders() {
+        let mut contract = VersionedContract::default();
+        let accounts = users(5);
+        for (i, account_id) in accounts.iter().enumerate() {
+            set_predecessor_and_deposit(account_id.clone(), (i as Balance + 1) * 10);
+            contract.deposit();
+        }
+
+        assert_eq!(contract.percentile(accounts[0].clone()), Some(0));
+        assert_eq!(contract.percentile(accounts[2].clone()), Some(50));
+        assert_eq!(contract.percentile(accounts[4].clone()), Some(100));
+        assert_eq!(contract.percentile(bob()), None);
+    }
+
+    #[test]
+    fn owner_must_be_named_account() {
+        let implicit: AccountId = "a".repeat(64).parse().unwrap();
+        let contract = VersionedContract::new(bob(), None);
+        assert_eq!(contract.get_owner(), bob());
+        assert!(catch_unwind(|| VersionedContract::new(implicit.clone(), None)).is_err());
+        assert!(catch_unwind(|| VersionedContract::new("system".parse().unwrap(), None)).is_err());
+
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        let result = catch_unwind(AssertUnwindSafe(|| contract.propose_owner(implicit)));
+        assert!(result.is_err());
+        contract.propose_owner(carol());
+
+        set_predecessor_and_deposit(carol(), 0);
+        contract.accept_owner();
+        assert_eq!(contract.get_owner(), carol());
+        assert_eq!(contract.get_pending_owner(), None);
+    }
+
+    #[test]
+    fn deposits_grouped_by_suffix() {
+        let mut contract = VersionedContract::default();
+        for (funder, deposit) in [
+            ("alice.near", 10),
+            ("bob.near", 5),
+            ("carol.tg", 7),
+            ("near", 1),
+        ] {
+            set_predecessor_and_deposit(funder.parse().unwrap(), deposit);
+            contract.deposit();
+        }
+
+        assert_eq!(
+            contract.deposits_by_suffix(0, 10),
+            HashMap::from([
+                ("near".to_string(), U128(15)),
+                ("tg".to_string(), U128(7)),
+                ("".to_string(), U128(1)),
+            ])
+        );
+        assert_eq!(
+            contract.deposits_by_suffix(0, 1),
+            HashMap::from([("near".to_string(), U128(10))])
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_PENDING_OWNER")]
+    fn accept_after_cancelled_transfer() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.propose_owner(carol());
+        contract.cancel_ownership_transfer();
+        assert_eq!(contract.get_pending_owner(), None);
+
+        set_predecessor_and_deposit(carol(), 0);
+        contract.accept_owner();
+    }
+
+    #[test]
+    fn locked_storage_cost_grows_with_funders() {
+        let mut contract = VersionedContract::default();
+        persist(&mut contract);
+        let before = contract.locked_storage_cost();
+
+        for funder in users(3) {
+            set_predecessor_and_deposit(funder, 10);
+            contract.deposit();
+        }
+        persist(&mut contract);
+        assert!(contract.locked_storage_cost().0 > before.0);
+    }
+
+    #[test]
+    fn migrate_single_legacy_funder() {
+        let mut contract = {
+            let mut funders = UnorderedMap::new(b"f");
+            funders.insert(bob(), 8);
+
+            VersionedContract::V1(ContractV1 {
+                funders,
+                nonce: 3,
+                hashes: HashMap::new(),
+            })
+        };
+
+        assert!(contract.migrate_funder(bob()));
+        assert_eq!(
+            contract.funder_version_histogram(),
+            HashMap::from([(FUNDER_INFO_VERSION, 1)])
+        );
+        // the legacy entry has no deposit history to carry over
+        assert_eq!(contract.last_deposit_nonce(bob()), None);
+        assert_eq!(contract.funders_since(0, 0, 10), Vec::<AccountId>::new());
+        assert_eq!(contract.funder_time_range(), None);
+        assert!(!contract.migrate_funder(bob()));
+        assert!(!contract.migrate_funder(carol()));
+    }
+
+    #[test]
+    fn deposit_returns_receipt() {
+        let mut contract = VersionedContract::default();
+        let mut builder = VMContextBuilder::new();
+        builder.predecessor_account_id(bob()).attached_deposit(10);
+        advance_time(&mut builder, 7);
+        contract.deposit();
+        let receipt = contract.deposit();
+
+        assert_eq!(
+            receipt,
+            DepositReceipt {
+                account_id: bob(),
+                amount: U128(10),
+                new_balance: U128(20),
+                nonce: 2,
+                timestamp: 7_000_000_000,
+            }
+        );
+    }
+
+    #[test]
+    fn pending_withdrawal_until_callback() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+        contract.withdraw(U128(4));
+        assert!(contract.has_pending_withdrawal(bob()));
+
+        testing_env!(
+            VMContextBuilder::new()
+                .predecessor_account_id(alice())
+                .build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])]
+        );
+        assert!(contract.on_withdraw_complete(bob(), U128(4)));
+        assert!(!contract.has_pending_withdrawal(bob()));
+        assert_eq!(contract.get_deposit(&bob()), Some(&6));
+    }
+
+    #[test]
+    fn fees_split_between_recipients() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_fee(1_000, None);
+        contract.set_fee_recipients(vec![(bob(), 6_000), (carol(), 4_000)]);
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            contract.set_fee_recipients(vec![(bob(), 6_000), (carol(), 3_000)])
+        }));
+        assert!(result.is_err());
+
+        let funder: AccountId = "dave.near".parse().unwrap();
+        set_predecessor_and_deposit(funder.clone(), 1_005);
+        contract.deposit();
+
+        // the fee of 100 is split 60/40
+        assert_eq!(contract.get_deposit(&funder), Some(&905));
+        assert_eq!(contract.get_deposit(&bob()), Some(&60));
+        assert_eq!(contract.get_deposit(&carol()), Some(&40));
+    }
+
+    #[test]
+    fn contract_version_is_independent_of_state() {
+        let contract = VersionedContract::V0(ContractV0::default());
+        assert_eq!(contract.contract_version(), CONTRACT_VERSION);
+        assert_eq!(contract.state_version(), 0);
+    }
+
+    #[test]
+    fn prune_removes_only_zero_balances() {
+        let mut contract = VersionedContract::default();
+        for funder in [bob(), carol(), alice()] {
+            set_predecessor_and_deposit(funder, 10);
+            contract.deposit();
+        }
+        for funder in [bob(), carol()] {
+            set_predecessor_and_deposit(funder, 0);
+            contract.withdraw(U128(10));
+        }
+
+        set_predecessor_and_deposit(alice(), 0);
+        assert_eq!(contract.prune_zero_balances(0, 10), 2);
+        assert_eq!(contract.get_funders(0, 10), vec![(alice(), U128(10))]);
+        assert_eq!(contract.prune_zero_balances(0, 10), 0);
+    }
+
+    #[test]
+    fn prune_by_repeating_window() {
+        let mut contract = VersionedContract::default();
+        for funder in users(4) {
+            set_predecessor_and_deposit(funder, 10);
+            contract.deposit();
+            contract.withdraw(U128(10));
+        }
+
+        set_predecessor_and_deposit(alice(), 0);
+        assert_eq!(contract.prune_zero_balances(0, 2), 2);
+        // the funders left moved into the window that was just scanned
+        assert_eq!(contract.prune_zero_balances(0, 2), 2);
+        assert_eq!(contract.prune_zero_balances(0, 2), 0);
+        assert_eq!(contract.num_funders(), 0);
+    }
+
+    #[test]
+    fn deposit_needed_for_rank() {
+        let mut contract = VersionedContract::default();
+        for (funder, deposit) in [(bob(), 30), (carol(), 50), (alice(), 10)] {
+            set_predecessor_and_deposit(funder, deposit);
+            contract.deposit();
+        }
+
+        assert_eq!(contract.deposit_for_rank(1), Some(U128(51)));
+        assert_eq!(contract.deposit_for_rank(3), Some(U128(11)));
+        assert_eq!(contract.deposit_for_rank(0), None);
+        assert_eq!(contract.deposit_for_rank(4), None);
+    }
+
+    #[test]
+    fn funder_time_range_spans_first_deposits() {
+        let mut contract = VersionedContract::default();
+        assert_eq!(contract.funder_time_range(), None);
+
+        let mut builder = VMContextBuilder::new();
+        builder.attached_deposit(5);
+        for funder in [bob(), carol(), bob()] {
+            builder.predecessor_account_id(funder);
+            advance_time(&mut builder, 10);
+            contract.deposit();
+        }
+
+        // bob's second deposit doesn't move the first deposit time
+        assert_eq!(
+            contract.funder_time_range(),
+            Some((10_000_000_000, 20_000_000_000))
+        );
+    }
+
+    #[test]
+    fn soft_deleted_record_hidden_from_reads() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        let k = "some_key".to_string();
+        contract.add_hash(k.clone());
+        contract.soft_delete_hash(k.clone());
+
+        assert_eq!(contract.get_balance(k.clone()), None);
+        assert_eq!(
+            contract.get_balance_with_deleted(k.clone(), true),
+            Some(BalancesV2 {
+                deposited: 1,
+                total: 1,
+                earned: 0,
+                updated_at: 0,
+                deleted: true,
+            })
+        );
+        // the key stays taken
+        let result = catch_unwind(AssertUnwindSafe(|| contract.add_hash(k.clone())));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn soft_deleted_record_is_not_usable() {
+        let mut contract = VersionedContract::default();
+        let k = derive_key(&bob(), "savings");
+        contract.add_hash(k.clone());
+        set_predecessor_and_deposit(alice(), 0);
+        contract.add_hash(bob().to_string());
+        contract.set_require_registration(true);
+        contract.soft_delete_hash(k.clone());
+        contract.soft_delete_hash(bob().to_string());
+
+        set_predecessor_and_deposit(bob(), 10);
+        let result = catch_unwind(AssertUnwindSafe(|| contract.deposit()));
+        assert!(result.is_err());
+
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_require_registration(false);
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            contract.allocate_to_record(k.clone(), U128(4))
+        }));
+        assert!(result.is_err());
+        assert_eq!(contract.get_deposit(&bob()), Some(&10));
+    }
+
+    #[test]
+    fn emit_full_state_in_chunks() {
+        let mut contract = VersionedContract::default();
+        for (funder, deposit) in [(bob(), 8), (carol(), 5), (alice(), 10)] {
+            set_predecessor_and_deposit(funder, deposit);
+            contract.deposit();
+        }
+
+        set_predecessor_and_deposit(alice(), 0);
+        assert_eq!(contract.emit_full_state(0, 2), 1);
+        let events = events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["event"], "state_snapshot");
+        assert_eq!(
+            events[0]["data"][0]["funders"],
+            json!([["bob.near", "8"], ["carol.near", "5"]])
+        );
+        assert_eq!(contract.emit_full_state(2, 2), 0);
+    }
+
+    #[test]
+    fn effective_min_first_deposit_takes_largest_bound() {
+        let mut contract = VersionedContract::default();
+        let storage_cost = FUNDER_STORAGE_BYTES as Balance * env::storage_byte_cost();
+        assert_eq!(contract.effective_min_first_deposit(), U128(storage_cost));
+
+        set_predecessor_and_deposit(alice(), 0);
+        contract.batch_admin(vec![AdminOp::SetMinDeposit(U128(storage_cost - 1))]);
+        assert_eq!(contract.effective_min_first_deposit(), U128(storage_cost));
+        contract.batch_admin(vec![AdminOp::SetMinDeposit(U128(storage_cost * 2))]);
+        assert_eq!(
+            contract.effective_min_first_deposit(),
+            U128(storage_cost * 2)
+        );
+    }
+
+    #[test]
+    fn delegate_administers_until_expiry() {
+        let mut contract = VersionedContract::default();
+        let mut builder = VMContextBuilder::new();
+        builder.predecessor_account_id(alice());
+        advance_time(&mut builder, 0);
+        contract.delegate_admin(bob(), 10_000_000_000);
+
+        builder.predecessor_account_id(bob());
+        advance_time(&mut builder, 5);
+        assert!(contract.can_administer(bob()));
+        contract.pause_withdrawals();
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            contract.delegate_admin(carol(), u64::MAX)
+        }));
+        assert!(result.is_err());
+
+        advance_time(&mut builder, 5);
+        assert!(!contract.can_administer(bob()));
+        let result = catch_unwind(AssertUnwindSafe(|| contract.unpause_withdrawals()));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn revoked_delegate_cannot_administer() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.delegate_admin(bob(), u64::MAX);
+        assert!(contract.can_administer(bob()));
+        contract.revoke_delegate();
+
+        set_predecessor_and_deposit(bob(), 0);
+        assert!(!contract.can_administer(bob()));
+        let result = catch_unwind(AssertUnwindSafe(|| contract.pause_withdrawals()));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn compute_change_matches_deposit() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_max_per_account(Some(U128(100)));
+        contract.set_fee(1_000, Some(carol()));
+
+        set_predecessor_and_deposit(bob(), 60);
+        contract.deposit();
+        let (credited, refunded) = contract.compute_change(bob(), U128(60));
+        // 46 fit under the cap, the fee of 4 is taken from those, so the balance ends at 96
+        assert_eq!((credited, refunded), (U128(42), U128(14)));
+
+        set_predecessor_and_deposit(bob(), 60);
+        let receipt = contract.deposit();
+        assert_eq!(receipt.amount, credited);
+        assert_eq!(contract.get_deposit(&bob()), Some(&96));
+        assert!(get_created_receipts()
+            .iter()
+            .any(|receipt| receipt.receiver_id == bob()));
+    }
+
+    #[test]
+    fn zero_funder_removes_balance() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+
+        set_predecessor_and_deposit(alice(), 0);
+        assert_eq!(contract.zero_funder(bob(), "dispute".to_string()), U128(10));
+        assert!(!contract.is_funder(bob()));
+        assert_eq!(contract.total_deposited(), U128(0));
+        assert!(get_created_receipts().is_empty());
+
+        assert_eq!(contract.zero_funder(bob(), "dispute".to_string()), U128(0));
+        let events = events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1]["event"], "zero_funder");
+    }
+
+    #[test]
+    fn zero_funder_forgets_cooldown() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_deposit_cooldown(Some(60));
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+
+        set_predecessor_and_deposit(alice(), 0);
+        contract.zero_funder(bob(), "dispute".to_string());
+        assert_eq!(contract.deposit_cooldown_remaining(bob()), 0);
+    }
+
+    #[test]
+    fn overall_yield_from_records() {
+        let mut contract = VersionedContract::default();
+        assert_eq!(contract.overall_yield_bps(), 0);
+
+        set_predecessor_and_deposit(alice(), 0);
+        for (k, deposited, earned) in [("a", 1_000, 50), ("b", 3_000, 150)] {
+            contract.set_balance(
+                k.to_string(),
+                BalancesV2 {
+                    deposited,
+                    total: deposited + earned,
+                    earned,
+                    updated_at: 0,
+                    deleted: false,
+                },
+                "earnings".to_string(),
+            );
+        }
+
+        // (50 + 150) * 10_000 / (1_000 + 3_000)
+        assert_eq!(contract.overall_yield_bps(), 500);
+    }
+
+    #[test]
+    fn seed_hashes_imports_v1_records() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.add_hash("a".to_string());
+        let seeded = contract.seed_hashes(vec![
+            (
+                "a".to_string(),
+                BalancesV1 {
+                    deposited: 5,
+                    total: 7,
+                    earned: 2,
+                },
+            ),
+            (
+                "b".to_string(),
+                BalancesV1 {
+                    deposited: 3,
+                    total: 3,
+                    earned: 0,
+                },
+            ),
+        ]);
+
+        assert_eq!(seeded, 2);
+        assert_eq!(
+            contract.get_hash_versions(0, 2),
+            vec![("a".to_string(), 1), ("b".to_string(), 1)]
+        );
+        assert_eq!(
+            contract.get_balance("a".to_string()),
+            Some(BalancesV2 {
+                deposited: 5,
+                total: 7,
+                earned: 2,
+                updated_at: 0,
+                deleted: false,
+            })
+        );
+        assert_eq!(
+            contract.get_balance("b".to_string()).map(|bal| bal.total),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn deposit_cooldown_counts_down() {
+        let mut contract = VersionedContract::default();
+        let mut builder = VMContextBuilder::new();
+        builder.predecessor_account_id(alice());
+        advance_time(&mut builder, 0);
+        contract.set_deposit_cooldown(Some(60));
+
+        builder.predecessor_account_id(bob()).attached_deposit(10);
+        advance_time(&mut builder, 0);
+        contract.deposit();
+        advance_time(&mut builder, 20);
+        assert_eq!(contract.deposit_cooldown_remaining(bob()), 40);
+        let result = catch_unwind(AssertUnwindSafe(|| contract.deposit()));
+        assert!(result.is_err());
+
+        advance_time(&mut builder, 40);
+        assert_eq!(contract.deposit_cooldown_remaining(bob()), 0);
+        contract.deposit();
+        assert_eq!(contract.get_deposit(&bob()), Some(&20));
+    }
+
+    #[test]
+    fn only_own_deposits_start_cooldown() {
+        let mut contract = {
+            let mut funders = UnorderedMap::new(b"f");
+            funders.insert(bob(), 8);
+
+            VersionedContract::V1(ContractV1 {
+                funders,
+                nonce: 0,
+                hashes: HashMap::new(),
+            })
+        };
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_deposit_cooldown(Some(60));
+
+        set_predecessor_and_deposit(carol(), 0);
+        assert!(contract.migrate_funder(bob()));
+        assert_eq!(contract.deposit_cooldown_remaining(bob()), 0);
+        set_predecessor_and_deposit(bob(), 0);
+        contract.withdraw(U128(3));
+        assert_eq!(contract.deposit_cooldown_remaining(bob()), 0);
+
+        // funding someone else starts the cooldown of the depositor only
+        set_predecessor_and_deposit(carol(), 10);
+        contract.deposit_split(vec![bob()]);
+        assert_eq!(contract.deposit_cooldown_remaining(bob()), 0);
+        assert_eq!(contract.deposit_cooldown_remaining(carol()), 60);
+
+        set_predecessor_and_deposit(bob(), 5);
+        contract.deposit();
+        assert_eq!(contract.deposit_cooldown_remaining(bob()), 60);
+    }
+
+    #[test]
+    fn fee_preview_matches_collected_fee() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_fee(250, Some(carol()));
+        let preview = contract.fee_preview(U128(1_000));
+        assert_eq!(preview, U128(25));
+
+        set_predecessor_and_deposit(bob(), 1_000);
+        contract.deposit();
+        assert_eq!(contract.get_deposit(&carol()), Some(&preview.0));
+    }
+
+    #[test]
+    fn funders_migrated_counts_upgraded_legacy_funders() {
+        let mut contract = {
+            let mut funders = UnorderedMap::new(b"f");
+            funders.insert(bob(), 8);
+            funders.insert(carol(), 8);
+            funders.insert(alice(), 8);
+
+            VersionedContract::V1(ContractV1 {
+                funders,
+                nonce: 0,
+                hashes: HashMap::new(),
+            })
+        };
+        assert_eq!(contract.funders_migrated(), 0);
+
+        set_predecessor_and_deposit(bob(), 2);
+        contract.deposit();
+        contract.deposit();
+        set_predecessor_and_deposit(carol(), 0);
+        contract.withdraw(U128(3));
+        assert!(contract.migrate_funder(alice()));
+        // a brand-new funder has nothing to migrate
+        set_predecessor_and_deposit("dave.near".parse().unwrap(), 2);
+        contract.deposit();
+
+        assert_eq!(contract.funders_migrated(), 3);
+        assert_eq!(
+            contract.funder_version_histogram(),
+            HashMap::from([(FUNDER_INFO_VERSION, 4)])
+        );
+    }
+
+    #[test]
+    fn balance_matches_claim() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+
+        assert!(contract.balance_matches(bob(), U128(10)));
+        assert!(!contract.balance_matches(bob(), U128(9)));
+        assert!(contract.balance_matches(carol(), U128(0)));
+    }
+
+    /// contract where bob deposited 10 and is frozen by the owner
+    fn contract_with_frozen_bob() -> VersionedContract {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.freeze_funder(bob());
+        set_predecessor_and_deposit(bob(), 0);
+        contract
+    }
+
+    #[test]
+    fn frozen_funder_balance_stays_readable() {
+        let contract = contract_with_frozen_bob();
+        assert!(contract.is_frozen(bob()));
+        assert_eq!(contract.get_deposit(&bob()), Some(&10));
+        assert_eq!(
+            contract.balance_after_withdraw(bob(), U128(4)),
+            Some(U128(6))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_FROZEN")]
+    fn frozen_funder_cannot_withdraw() {
+        contract_with_frozen_bob().withdraw(U128(4));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_FROZEN")]
+    fn frozen_funder_cannot_transfer() {
+        contract_with_frozen_bob().transfer_deposit(carol(), U128(4));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_FROZEN")]
+    fn frozen_funder_cannot_allocate() {
+        contract_with_frozen_bob().allocate_to_record(derive_key(&bob(), "savings"), U128(4));
+    }
+
+    #[test]
+    fn sweep_dust_skips_frozen_funders() {
+        let mut contract = contract_with_frozen_bob();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_fee(0, Some(carol()));
+        assert_eq!(contract.sweep_dust(U128(20), 0, 10), U128(0));
+        assert_eq!(contract.get_deposit(&bob()), Some(&10));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_FROZEN")]
+    fn frozen_funder_cannot_be_zeroed() {
+        let mut contract = contract_with_frozen_bob();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.zero_funder(bob(), "dispute".to_string());
+    }
+
+    #[test]
+    fn unfrozen_funder_can_withdraw_and_transfer() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.freeze_funder(bob());
+        contract.unfreeze_funder(bob());
+
+        set_predecessor_and_deposit(bob(), 0);
+        contract.withdraw(U128(4));
+        contract.transfer_deposit(carol(), U128(2));
+        assert_eq!(contract.get_deposit(&bob()), Some(&4));
+        assert_eq!(contract.get_deposit(&carol()), Some(&2));
+    }
+
+    #[test]
+    fn transfer_deposit_respects_receiver_limits() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+        set_predecessor_and_deposit(alice(), 0);
+        contract.set_max_funders(Some(2));
+        contract.set_max_per_account(Some(U128(5)));
+        contract.batch_admin(vec![AdminOp::Ban(carol())]);
+
+        set_predecessor_and_deposit(bob(), 0);
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            contract.transfer_deposit(carol(), U128(1))
+        }));
+        assert!(result.is_err());
+
+        let users = users(2);
+        contract.transfer_deposit(users[0].clone(), U128(5));
+        // a third funder is above `max_funders`
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            contract.transfer_deposit(users[1].clone(), U128(1))
+        }));
+        assert!(result.is_err());
+        // the receiver's balance is above `max_per_account`
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            contract.transfer_deposit(users[0].clone(), U128(1))
+        }));
+        assert!(result.is_err());
+        assert_eq!(contract.num_funders(), 2);
+    }
+
+    #[test]
+    fn supported_standards_include_events() {
+        let contract = VersionedContract::default();
+        assert_eq!(
+            contract.supported_standards(),
+            vec![("nep297".to_string(), "1.0.0".to_string())]
+        );
+    }
+
+    #[test]
+    fn deposit_histogram_buckets() {
+        let mut contract = VersionedContract::default();
+        for (i, deposit) in [1, 5, 10, 50, 120].into_iter().enumerate() {
+            set_predecessor_and_deposit(users(5)[i].clone(), deposit);
+            contract.deposit();
+        }
+
+        let edges = vec![U128(5), U128(50), U128(100)];
+        assert_eq!(
+            contract.deposit_histogram(edges.clone(), 0, 10),
+            vec![1, 2, 1, 1]
+        );
+        assert_eq!(contract.deposit_histogram(edges, 0, 2), vec![1, 1, 0, 0]);
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            contract.deposit_histogram(vec![U128(5), U128(5)], 0, 10)
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn admin_changes_record_reason() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+
+        set_predecessor_and_deposit(alice(), 0);
+        contract.zero_funder(bob(), "chargeback #12".to_string());
+        let events = events();
+        assert_eq!(events[0]["data"][0]["reason"], "chargeback #12");
+
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            contract.zero_funder(carol(), "a".repeat(MAX_REASON_LENGTH + 1))
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn total_events_counts_emitted_events() {
+        let mut contract = VersionedContract::default();
+        set_predecessor_and_deposit(bob(), 10);
+        contract.deposit();
+        contract.deposit();
+        contract.withdraw(U128(5));
+        let mut emitted = events().len();
+
+        // the test context is reset, so the logs start over
+        set_predecessor_and_deposit(alice(), 0);
+        contract.freeze_funder(bob());
+        emitted += events().len();
+        contract.reemit_recent(0, 10);
+
+        assert_eq!(emitted, 4);
+        assert_eq!(contract.total_events(), emitted as u64);
     }
 }
@@ -0,0 +1,72 @@
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{AccountId, Gas};
+
+use crate::contracts::Limits;
+
+/// A window of funders and balance record versions, for cross-checking both during a migration
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AuditPage {
+    /// funders with their balance, in `funders` iteration order
+    pub funders: Vec<(AccountId, U128)>,
+    /// `hashes` keys with the version of their stored record, sorted by key
+    pub hash_versions: Vec<(String, u8)>,
+}
+
+/// Every setting of the contract in one place, for snapshotting and diffing deployments
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Config {
+    pub owner_id: AccountId,
+    pub limits: Limits,
+    pub goal: U128,
+    pub fee_bps: u16,
+    pub fee_account: Option<AccountId>,
+    pub fee_recipients: Vec<(AccountId, u16)>,
+    pub fee_round_up: bool,
+    pub require_registration: bool,
+    pub allowlist_enabled: bool,
+    pub withdrawals_paused: bool,
+    pub lenient_reads: bool,
+    pub upgrade_policy: UpgradePolicy,
+    pub event_prefix: String,
+    pub callback_gas: Gas,
+}
+
+/// Confirmation of a deposit, returned by `deposit`
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DepositReceipt {
+    pub account_id: AccountId,
+    /// amount credited to the depositor, after the fee and any refund above the cap
+    pub amount: U128,
+    pub new_balance: U128,
+    /// global nonce right after the deposit
+    pub nonce: u64,
+    pub timestamp: u64,
+}
+
+/// Describes how this contract is upgraded, for governance transparency
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct UpgradePolicy {
+    /// whether `deploy_code` can deploy new code. Access keys of the contract account can deploy
+    /// code regardless, the policy only covers the contract's own upgrade path.
+    pub code_upgrades_allowed: bool,
+    /// account allowed to `deploy_code`, such as a DAO, the owner when unset
+    pub upgrader_id: Option<AccountId>,
+    /// Always `true`: the state is upgraded on the fly by the first call mutating it, `migrate`
+    /// only does it earlier.
+    pub automatic_migration: bool,
+}
+
+impl Default for UpgradePolicy {
+    fn default() -> Self {
+        Self {
+            code_upgrades_allowed: true,
+            upgrader_id: None,
+            automatic_migration: true,
+        }
+    }
+}